@@ -1,26 +1,135 @@
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, str::FromStr, time::Duration};
 
 use anyhow::{Context as _, Result};
 use iroh::{EndpointAddr, EndpointId, PublicKey, SecretKey, Signature};
+use iroh_blobs::{Hash, ticket::BlobTicket};
 use iroh_gossip::TopicId;
 use serde::{Deserialize, Serialize};
 
+/// Version byte prepended to serialized tickets, legacy tickets have no version byte
+const TICKET_VERSION: u8 = 1;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Ticket {
     pub topic: TopicId,
     pub endpoints: Vec<EndpointAddr>,
+    pub room: Option<String>,
+    /// Expiry timestamp in milliseconds since UNIX epoch
+    pub expires: Option<u64>,
+    pub inviter: Option<Inviter>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Inviter {
+    pub id: EndpointId,
+    pub name: String,
+    pub signature: Signature,
+}
+
+/// Original ticket format - just topic and endpoints
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyTicket {
+    topic: TopicId,
+    endpoints: Vec<EndpointAddr>,
+}
+
+#[derive(Serialize)]
+struct SignedTicketPart<'a> {
+    topic: &'a TopicId,
+    endpoints: &'a [EndpointAddr],
+    room: &'a Option<String>,
+    expires: Option<u64>,
+    name: &'a str,
+}
+
+impl Ticket {
+    pub fn new(topic: TopicId, endpoints: Vec<EndpointAddr>) -> Self {
+        Ticket {
+            topic,
+            endpoints,
+            room: None,
+            expires: None,
+            inviter: None,
+        }
+    }
+
+    pub fn with_room(mut self, room: Option<String>) -> Self {
+        self.room = room;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.expires =
+            ttl.map(|ttl| now().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)));
+        self
+    }
+
+    fn signed_data(&self, name: &str) -> Result<Vec<u8>> {
+        let part = SignedTicketPart {
+            topic: &self.topic,
+            endpoints: &self.endpoints,
+            room: &self.room,
+            expires: self.expires,
+            name,
+        };
+        Ok(postcard::to_stdvec(&part)?)
+    }
+
+    /// Signs ticket by inviter key, must be called after all other fields are set
+    pub fn sign(mut self, key: &SecretKey, name: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        let signature = key.sign(&self.signed_data(&name)?);
+        self.inviter = Some(Inviter {
+            id: key.public(),
+            name,
+            signature,
+        });
+        Ok(self)
+    }
+
+    /// Checks expiry and inviter signature (if ticket is signed)
+    ///
+    /// Unsigned ticket passes, but anyone could have removed its signature and expiry.
+    pub fn verify(&self) -> Result<()> {
+        if let Some(expires) = self.expires
+            && expires < now()
+        {
+            anyhow::bail!("Ticket expired");
+        }
+        if let Some(inviter) = &self.inviter {
+            inviter
+                .id
+                .verify(&self.signed_data(&inviter.name)?, &inviter.signature)
+                .context("Invalid ticket signature")?;
+        }
+        Ok(())
+    }
 }
 
 impl TryFrom<&[u8]> for Ticket {
     type Error = anyhow::Error;
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-        Ok(postcard::from_bytes(bytes)?)
+        // tickets are decoded completely, so corrupted ticket is not taken for other format
+        let legacy = match postcard::take_from_bytes::<LegacyTicket>(bytes) {
+            Ok((legacy, [])) => Some(Ticket::new(legacy.topic, legacy.endpoints)),
+            _ => None,
+        };
+        match bytes.split_first() {
+            Some((&TICKET_VERSION, rest)) => match postcard::take_from_bytes::<Ticket>(rest) {
+                Ok((ticket, [])) => Ok(ticket),
+                // legacy ticket starts with topic, so it can start with version byte too
+                _ => legacy.context("Corrupted ticket"),
+            },
+            _ => legacy.context("Invalid ticket"),
+        }
     }
 }
 
-impl Into<Vec<u8>> for &Ticket {
-    fn into(self) -> Vec<u8> {
-        postcard::to_stdvec(self).unwrap()
+impl From<&Ticket> for Vec<u8> {
+    fn from(ticket: &Ticket) -> Self {
+        let mut data = vec![TICKET_VERSION];
+        data.extend(postcard::to_stdvec(ticket).unwrap());
+        data
     }
 }
 
@@ -52,6 +161,156 @@ pub struct Message {
 pub enum MessageBody {
     Intro { name: String },
     Message { text: String },
+    KeyRotation { chain: Vec<KeyHandover> },
+}
+
+/// Metadata of shared file, so downloader can restore its name and attributes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    /// Modification time in seconds since UNIX epoch
+    pub modified: Option<u64>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    pub protected: bool,
+}
+
+impl FileInfo {
+    pub fn from_metadata(name: String, metadata: &std::fs::Metadata, protected: bool) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt as _;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        FileInfo {
+            name,
+            size: metadata.len(),
+            modified,
+            mode,
+            protected,
+        }
+    }
+}
+
+/// Prefix of file tickets, distinguishes them from plain blob tickets
+const FILE_TICKET_PREFIX: &str = "file";
+
+/// Blob ticket carrying metadata of shared file, so it is available wherever ticket goes
+///
+/// Plain blob tickets are accepted too, they have no metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTicket {
+    pub blob: BlobTicket,
+    pub info: Option<FileInfo>,
+}
+
+impl FileTicket {
+    pub fn new(blob: BlobTicket, info: FileInfo) -> Self {
+        FileTicket {
+            blob,
+            info: Some(info),
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.blob.hash()
+    }
+}
+
+impl Display for FileTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.info.is_none() {
+            return write!(f, "{}", self.blob);
+        }
+        let mut data = vec![TICKET_VERSION];
+        data.extend(postcard::to_stdvec(self).map_err(|_| std::fmt::Error)?);
+        let mut encoded = data_encoding::BASE32_NOPAD.encode(&data);
+        encoded.make_ascii_lowercase();
+        write!(f, "{FILE_TICKET_PREFIX}{encoded}")
+    }
+}
+
+impl FromStr for FileTicket {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // tickets are upper-cased in QR codes
+        let s = s.to_ascii_lowercase();
+        let Some(encoded) = s.strip_prefix(FILE_TICKET_PREFIX) else {
+            return Ok(FileTicket {
+                blob: s.parse()?,
+                info: None,
+            });
+        };
+        let data = data_encoding::BASE32_NOPAD.decode(encoded.to_ascii_uppercase().as_bytes())?;
+        match data.split_first() {
+            Some((&TICKET_VERSION, rest)) => Ok(postcard::from_bytes(rest)?),
+            _ => anyhow::bail!("Unsupported file ticket version"),
+        }
+    }
+}
+
+/// Statement signed by old key, that identity continues with new key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyHandover {
+    pub old: PublicKey,
+    pub new: PublicKey,
+    pub ts: u64,
+    pub signature: Signature,
+}
+
+impl KeyHandover {
+    fn signed_data(old: &PublicKey, new: &PublicKey, ts: u64) -> Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(&(
+            "send-file key handover",
+            old,
+            new,
+            ts,
+        ))?)
+    }
+
+    pub fn new(old_key: &SecretKey, new: PublicKey) -> Result<Self> {
+        let old = old_key.public();
+        let ts = now();
+        let signature = old_key.sign(&Self::signed_data(&old, &new, ts)?);
+        Ok(KeyHandover {
+            old,
+            new,
+            ts,
+            signature,
+        })
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        self.old
+            .verify(
+                &Self::signed_data(&self.old, &self.new, self.ts)?,
+                &self.signature,
+            )
+            .context("Invalid handover signature")
+    }
+
+    /// Verifies chain of handovers, which must be continuous and end with `current` key
+    pub fn verify_chain(chain: &[KeyHandover], current: &PublicKey) -> Result<()> {
+        for handover in chain {
+            handover.verify()?;
+        }
+        if chain.windows(2).any(|pair| pair[0].new != pair[1].old) {
+            anyhow::bail!("Broken handover chain");
+        }
+        match chain.last() {
+            Some(last) if last.new == *current => Ok(()),
+            Some(_) => anyhow::bail!("Handover chain does not end with sender key"),
+            None => anyhow::bail!("Empty handover chain"),
+        }
+    }
 }
 
 impl Message {
@@ -72,6 +331,14 @@ impl Message {
         }
     }
 
+    pub fn new_key_rotation(chain: Vec<KeyHandover>) -> Self {
+        Message {
+            body: MessageBody::KeyRotation { chain },
+            id: uuid::Uuid::new_v4(),
+            ts: now(),
+        }
+    }
+
     pub fn sign_and_encode(&self, key: &SecretKey) -> Result<Vec<u8>> {
         let data = postcard::to_stdvec(self)?;
         let signature = key.sign(&data);
@@ -91,9 +358,9 @@ impl TryFrom<&[u8]> for Message {
     }
 }
 
-impl Into<Vec<u8>> for &Message {
-    fn into(self) -> Vec<u8> {
-        postcard::to_stdvec(self).unwrap()
+impl From<&Message> for Vec<u8> {
+    fn from(message: &Message) -> Self {
+        postcard::to_stdvec(message).unwrap()
     }
 }
 
@@ -130,3 +397,221 @@ fn now() -> u64 {
     )
     .expect("TS bigger than u64")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ticket() -> Ticket {
+        let key = SecretKey::generate(&mut rand::rng());
+        Ticket::new(
+            TopicId::from_bytes(rand::random()),
+            vec![EndpointAddr::new(key.public())],
+        )
+    }
+
+    #[test]
+    fn legacy_ticket_is_parsed() {
+        let ticket = test_ticket();
+        let legacy = LegacyTicket {
+            topic: ticket.topic,
+            endpoints: ticket.endpoints.clone(),
+        };
+        let encoded = data_encoding::BASE32_NOPAD.encode(&postcard::to_stdvec(&legacy).unwrap());
+        let parsed: Ticket = encoded.to_ascii_lowercase().parse().unwrap();
+        assert_eq!(parsed.topic, ticket.topic);
+        assert_eq!(parsed.endpoints, ticket.endpoints);
+        assert!(parsed.room.is_none() && parsed.expires.is_none() && parsed.inviter.is_none());
+        parsed.verify().unwrap();
+    }
+
+    #[test]
+    fn versioned_ticket_round_trip() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let ticket = test_ticket()
+            .with_room(Some("team".to_string()))
+            .with_ttl(Some(Duration::from_secs(60)))
+            .sign(&key, "alice")
+            .unwrap();
+        let parsed: Ticket = ticket.to_string().parse().unwrap();
+        assert_eq!(parsed.topic, ticket.topic);
+        assert_eq!(parsed.endpoints, ticket.endpoints);
+        assert_eq!(parsed.room.as_deref(), Some("team"));
+        assert_eq!(parsed.expires, ticket.expires);
+        let inviter = parsed.inviter.as_ref().unwrap();
+        assert_eq!(inviter.id, key.public());
+        assert_eq!(inviter.name, "alice");
+        parsed.verify().unwrap();
+    }
+
+    #[test]
+    fn expired_ticket_is_rejected() {
+        let mut ticket = test_ticket();
+        ticket.expires = Some(now() - 1);
+        let parsed: Ticket = ticket.to_string().parse().unwrap();
+        assert!(parsed.verify().unwrap_err().to_string().contains("expired"));
+    }
+
+    #[test]
+    fn huge_ttl_does_not_overflow() {
+        let ticket = test_ticket().with_ttl(Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(ticket.expires, Some(u64::MAX));
+        ticket.verify().unwrap();
+    }
+
+    #[test]
+    fn tampered_ticket_is_rejected() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let ticket = test_ticket()
+            .with_room(Some("team".to_string()))
+            .sign(&key, "alice")
+            .unwrap();
+        let mut parsed: Ticket = ticket.to_string().parse().unwrap();
+        parsed.room = Some("other".to_string());
+        assert!(parsed.verify().is_err());
+
+        let mut parsed: Ticket = ticket.to_string().parse().unwrap();
+        parsed.inviter.as_mut().unwrap().name = "mallory".to_string();
+        assert!(parsed.verify().is_err());
+    }
+
+    #[test]
+    fn corrupted_ticket_is_rejected() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let ticket = test_ticket()
+            .with_room(Some("team".to_string()))
+            .sign(&key, "alice")
+            .unwrap();
+        let data: Vec<u8> = (&ticket).into();
+        let err = Ticket::try_from(&data[..data.len() - 10]).unwrap_err();
+        assert!(err.to_string().contains("Corrupted ticket"));
+    }
+
+    #[test]
+    fn legacy_ticket_starting_with_version_byte_is_parsed() {
+        let mut ticket = test_ticket();
+        ticket.topic = TopicId::from_bytes([TICKET_VERSION; 32]);
+        let legacy = LegacyTicket {
+            topic: ticket.topic,
+            endpoints: ticket.endpoints.clone(),
+        };
+        let parsed = Ticket::try_from(postcard::to_stdvec(&legacy).unwrap().as_slice()).unwrap();
+        assert_eq!(parsed.topic, ticket.topic);
+        assert!(parsed.inviter.is_none());
+    }
+
+    fn test_blob_ticket() -> BlobTicket {
+        let key = SecretKey::generate(&mut rand::rng());
+        BlobTicket::new(
+            EndpointAddr::new(key.public()),
+            Hash::new(b"content"),
+            iroh_blobs::BlobFormat::Raw,
+        )
+    }
+
+    #[test]
+    fn file_ticket_round_trip() {
+        let info = FileInfo {
+            name: "report.pdf".to_string(),
+            size: 1234,
+            modified: Some(1_700_000_000),
+            mode: Some(0o644),
+            protected: true,
+        };
+        let ticket = FileTicket::new(test_blob_ticket(), info);
+        let encoded = ticket.to_string();
+        assert!(encoded.starts_with(FILE_TICKET_PREFIX));
+        let parsed: FileTicket = encoded.parse().unwrap();
+        assert_eq!(parsed.blob, ticket.blob);
+        let info = parsed.info.unwrap();
+        assert_eq!(info.name, "report.pdf");
+        assert_eq!(info.size, 1234);
+        assert_eq!(info.modified, Some(1_700_000_000));
+        assert_eq!(info.mode, Some(0o644));
+        assert!(info.protected);
+    }
+
+    #[test]
+    fn plain_blob_ticket_is_file_ticket_without_info() {
+        let blob = test_blob_ticket();
+        let parsed: FileTicket = blob.to_string().parse().unwrap();
+        assert_eq!(parsed.blob, blob);
+        assert!(parsed.info.is_none());
+        assert_eq!(parsed.to_string(), blob.to_string());
+        assert!("fileinvalid".parse::<FileTicket>().is_err());
+        assert!("blobinvalid".parse::<FileTicket>().is_err());
+    }
+
+    #[test]
+    fn upper_case_tickets_are_parsed() {
+        let blob = test_blob_ticket();
+        let parsed: FileTicket = blob.to_string().to_ascii_uppercase().parse().unwrap();
+        assert_eq!(parsed.blob, blob);
+
+        let info = FileInfo {
+            name: "a.txt".to_string(),
+            size: 1,
+            modified: None,
+            mode: None,
+            protected: false,
+        };
+        let ticket = FileTicket::new(blob, info).to_string().to_ascii_uppercase();
+        assert_eq!(
+            ticket.parse::<FileTicket>().unwrap().info.unwrap().name,
+            "a.txt"
+        );
+    }
+
+    #[test]
+    fn garbage_is_not_ticket() {
+        assert!("notaticket".parse::<Ticket>().is_err());
+        assert!("".parse::<Ticket>().is_err());
+    }
+
+    /// Keys and chain of handovers between consecutive keys
+    fn test_chain(len: usize) -> (Vec<SecretKey>, Vec<KeyHandover>) {
+        let keys: Vec<SecretKey> = (0..=len)
+            .map(|_| SecretKey::generate(&mut rand::rng()))
+            .collect();
+        let chain = keys
+            .windows(2)
+            .map(|pair| KeyHandover::new(&pair[0], pair[1].public()).unwrap())
+            .collect();
+        (keys, chain)
+    }
+
+    #[test]
+    fn handover_chain_is_verified() {
+        let (keys, chain) = test_chain(2);
+        KeyHandover::verify_chain(&chain, &keys[2].public()).unwrap();
+        KeyHandover::verify_chain(&chain[..1], &keys[1].public()).unwrap();
+    }
+
+    #[test]
+    fn forged_handover_is_rejected() {
+        let (keys, mut chain) = test_chain(1);
+        // signed by someone else than old key
+        let forger = SecretKey::generate(&mut rand::rng());
+        let forged = KeyHandover::new(&forger, keys[1].public()).unwrap();
+        chain[0].signature = forged.signature;
+        assert!(KeyHandover::verify_chain(&chain, &keys[1].public()).is_err());
+    }
+
+    #[test]
+    fn broken_handover_chain_is_rejected() {
+        let (keys, mut chain) = test_chain(2);
+        let (other, _) = test_chain(1);
+        chain[1] = KeyHandover::new(&other[0], keys[2].public()).unwrap();
+        let err = KeyHandover::verify_chain(&chain, &keys[2].public()).unwrap_err();
+        assert!(err.to_string().contains("Broken"));
+    }
+
+    #[test]
+    fn handover_chain_must_end_with_sender() {
+        let (keys, chain) = test_chain(2);
+        assert!(KeyHandover::verify_chain(&chain, &keys[1].public()).is_err());
+        let stranger = SecretKey::generate(&mut rand::rng()).public();
+        assert!(KeyHandover::verify_chain(&chain, &stranger).is_err());
+        assert!(KeyHandover::verify_chain(&[], &keys[0].public()).is_err());
+    }
+}
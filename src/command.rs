@@ -1,20 +1,144 @@
 use std::str::FromStr;
 
-use crate::channel::Message;
+use crate::{
+    channel::Message,
+    limits::{Direction, LimitChange},
+    queue::{Priority, QueueCommand},
+    store::StoreCommand,
+};
 use anyhow::{anyhow, bail};
 
+/// Password of command, parsing only records that `--password` option was given
+#[derive(Default)]
+pub enum Password {
+    #[default]
+    None,
+    /// Password must be entered by [`Command::prompt_password`] before command is run
+    Requested,
+    Given(String),
+}
+
+impl Password {
+    /// Returns entered password, fails if it was requested but not entered
+    pub fn into_given(self) -> anyhow::Result<Option<String>> {
+        match self {
+            Password::None => Ok(None),
+            Password::Requested => bail!("Password was not entered"),
+            Password::Given(password) => Ok(Some(password)),
+        }
+    }
+}
+
 pub enum Command {
     Share {
         file: String,
+        password: Password,
+        /// Reference file in place instead of copying it into store
+        reference: bool,
     },
     Download {
         ticket: String,
         output_file: Option<String>,
+        password: Password,
+        /// Overwrite existing output file
+        force: bool,
+        priority: Priority,
+    },
+    Invite,
+    Qr {
+        ticket: Option<String>,
+    },
+    Code {
+        ticket: Option<String>,
+    },
+    Net,
+    Store(StoreCommand),
+    Queue(QueueCommand),
+    /// Shows limits if no change is given
+    Limit(Option<LimitChange>),
+    Verify {
+        file: String,
+        /// Blob ticket or hash to compare with
+        expected: String,
     },
     Message(Message),
     Quit,
 }
 
+const PASSWORD_OPTION: &str = "--password";
+const REF_OPTION: &str = "--ref";
+const FORCE_OPTION: &str = "--force";
+const HIGH_OPTION: &str = "--high";
+const LOW_OPTION: &str = "--low";
+
+/// Strips flag option from params, returns if it was present
+fn flag_option<'a>(params: &'a str, option: &str) -> (&'a str, bool) {
+    match params.strip_prefix(option) {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => (rest.trim_start(), true),
+        _ => (params, false),
+    }
+}
+
+/// Strips `--password` option from params, password itself is asked for after parsing
+fn password_option(params: &str) -> (&str, Password) {
+    match flag_option(params, PASSWORD_OPTION) {
+        (rest, true) => (rest, Password::Requested),
+        (rest, false) => (rest, Password::None),
+    }
+}
+
+/// Strips options of `#download` given in any order, returns rest of params
+fn download_options<'a>(
+    mut params: &'a str,
+    force: &mut bool,
+    priority: &mut Priority,
+    password: &mut Password,
+) -> &'a str {
+    loop {
+        if let (rest, true) = flag_option(params, FORCE_OPTION) {
+            *force = true;
+            params = rest;
+        } else if let (rest, true) = flag_option(params, HIGH_OPTION) {
+            *priority = Priority::High;
+            params = rest;
+        } else if let (rest, true) = flag_option(params, LOW_OPTION) {
+            *priority = Priority::Low;
+            params = rest;
+        } else if let (rest, Password::Requested) = password_option(params) {
+            *password = Password::Requested;
+            params = rest;
+        } else {
+            return params;
+        }
+    }
+}
+
+impl Command {
+    /// Prompts on terminal for password requested by `--password` option
+    ///
+    /// Must be called when line editor is not active, so prompt is not mixed with its output.
+    /// Shared files ask for password twice, so typo does not make them unreadable.
+    pub fn prompt_password(&mut self) -> anyhow::Result<()> {
+        let (password, confirm) = match self {
+            Command::Share { password, .. } => (password, true),
+            Command::Download { password, .. } => (password, false),
+            _ => return Ok(()),
+        };
+        if !matches!(password, Password::Requested) {
+            return Ok(());
+        }
+        let entered = rpassword::prompt_password("Password: ")?;
+        if entered.is_empty() {
+            bail!("Empty password");
+        }
+        if confirm && rpassword::prompt_password("Repeat password: ")? != entered {
+            bail!("Passwords do not match");
+        }
+        *password = Password::Given(entered);
+        Ok(())
+    }
+}
+
 impl FromStr for Command {
     type Err = anyhow::Error;
 
@@ -22,21 +146,149 @@ impl FromStr for Command {
         if s.starts_with("#") {
             let mut parts = s.splitn(2, ' ');
             match parts.next().unwrap() {
-                "#share" | "#s" => Ok(Command::Share {
-                    file: parts
-                        .next()
-                        .ok_or_else(|| anyhow!("Missing part"))?
-                        .to_string(),
-                }),
+                "#share" | "#s" => {
+                    let params = parts.next().ok_or_else(|| anyhow!("Missing part"))?;
+                    let (mut params, mut reference, mut password) = (params, false, Password::None);
+                    // options can be given in any order, file name follows them
+                    loop {
+                        if let (rest, true) = flag_option(params, REF_OPTION) {
+                            reference = true;
+                            params = rest;
+                        } else if let (rest, Password::Requested) = password_option(params) {
+                            password = Password::Requested;
+                            params = rest;
+                        } else {
+                            break;
+                        }
+                    }
+                    if params.is_empty() {
+                        bail!("Missing file");
+                    }
+                    Ok(Command::Share {
+                        file: params.to_string(),
+                        password,
+                        reference,
+                    })
+                }
                 "#download" | "#d" => {
                     let params = parts.next().ok_or_else(|| anyhow!("Missing part"))?;
-                    let mut parts = params.splitn(2, ' ');
+                    let (mut force, mut password) = (false, Password::None);
+                    let mut priority = Priority::Normal;
+                    // options can be given before and after ticket, output file name is last
+                    let params = download_options(params, &mut force, &mut priority, &mut password);
+                    let (ticket, rest) = params.split_once(' ').unwrap_or((params, ""));
+                    if ticket.is_empty() {
+                        bail!("Missing ticket");
+                    }
+                    let rest = download_options(rest, &mut force, &mut priority, &mut password);
+                    let output_file = match rest {
+                        "" => None,
+                        file if file.starts_with("--") => {
+                            bail!("Unknown option {file}, options must precede output file")
+                        }
+                        file => Some(file.to_string()),
+                    };
                     Ok(Command::Download {
-                        ticket: parts
-                            .next()
-                            .ok_or_else(|| anyhow!("Missing part"))?
-                            .to_string(),
-                        output_file: parts.next().map(|s| s.to_string()),
+                        ticket: ticket.to_string(),
+                        output_file,
+                        password,
+                        force,
+                        priority,
+                    })
+                }
+                "#invite" | "#i" => Ok(Command::Invite),
+                "#qr" => Ok(Command::Qr {
+                    ticket: parts.next().map(|s| s.trim().to_string()),
+                }),
+                "#code" | "#c" => Ok(Command::Code {
+                    ticket: parts.next().map(|s| s.trim().to_string()),
+                }),
+                "#net" | "#n" | "#doctor" => Ok(Command::Net),
+                "#store" => {
+                    let params = parts.next().unwrap_or_default().trim();
+                    let mut parts = params.splitn(2, ' ');
+                    let cmd = match parts.next().unwrap_or_default() {
+                        "" | "list" | "ls" => StoreCommand::List,
+                        "remove" | "rm" => StoreCommand::Remove {
+                            hash: parts
+                                .next()
+                                .ok_or_else(|| anyhow!("Missing hash"))?
+                                .trim()
+                                .to_string(),
+                        },
+                        "usage" | "du" => StoreCommand::Usage,
+                        cmd => bail!("Unknown store command {cmd}"),
+                    };
+                    Ok(Command::Store(cmd))
+                }
+                "#queue" => {
+                    let params: Vec<&str> = parts
+                        .next()
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .collect();
+                    let id = |id: &str| -> anyhow::Result<u64> {
+                        id.trim_start_matches('#')
+                            .parse()
+                            .map_err(|_| anyhow!("Invalid download id {id}"))
+                    };
+                    let cmd = match params.as_slice() {
+                        [] | ["list" | "ls"] => QueueCommand::List,
+                        ["move" | "mv", job, position] => QueueCommand::Move {
+                            id: id(job)?,
+                            position: position.parse()?,
+                        },
+                        ["top", job] => QueueCommand::Move {
+                            id: id(job)?,
+                            position: 1,
+                        },
+                        ["priority" | "prio", job, priority] => QueueCommand::Priority {
+                            id: id(job)?,
+                            priority: priority.parse()?,
+                        },
+                        ["cancel" | "rm", job] => QueueCommand::Cancel { id: id(job)? },
+                        ["parallel", parallelism] => QueueCommand::Parallel(parallelism.parse()?),
+                        _ => bail!(
+                            "Usage: #queue [list|move <id> <position>|top <id>|priority <id> <low|normal|high>|cancel <id>|parallel <n>]"
+                        ),
+                    };
+                    Ok(Command::Queue(cmd))
+                }
+                "#limit" | "#l" => {
+                    let params: Vec<&str> = parts
+                        .next()
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .collect();
+                    let (direction, per_peer, rate) = match params.as_slice() {
+                        [] => return Ok(Command::Limit(None)),
+                        [direction, rate] => (*direction, false, *rate),
+                        [direction, "peer", rate] => (*direction, true, *rate),
+                        _ => bail!("Usage: #limit [up|down] [peer] <KiB/s>"),
+                    };
+                    let direction = match direction {
+                        "up" | "upload" => Direction::Upload,
+                        "down" | "download" => Direction::Download,
+                        direction => bail!("Unknown direction {direction}, use up or down"),
+                    };
+                    let rate = match rate {
+                        "off" | "none" => 0,
+                        rate => rate.parse()?,
+                    };
+                    Ok(Command::Limit(Some(LimitChange {
+                        direction,
+                        per_peer,
+                        rate,
+                    })))
+                }
+                "#verify" | "#v" => {
+                    let params = parts.next().ok_or_else(|| anyhow!("Missing part"))?.trim();
+                    let (file, expected) = params
+                        .rsplit_once(' ')
+                        .ok_or_else(|| anyhow!("Usage: #verify <file> <ticket|hash>"))?;
+                    Ok(Command::Verify {
+                        file: file.trim().to_string(),
+                        expected: expected.to_string(),
                     })
                 }
                 "#quit" | "#q" => Ok(Command::Quit),
@@ -47,3 +299,77 @@ impl FromStr for Command {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_must_be_entered_when_requested() {
+        assert_eq!(Password::None.into_given().unwrap(), None);
+        assert_eq!(
+            Password::Given("secret".to_string()).into_given().unwrap(),
+            Some("secret".to_string())
+        );
+        assert!(Password::Requested.into_given().is_err());
+    }
+
+    fn share(line: &str) -> (String, bool, bool) {
+        match line.parse().unwrap() {
+            Command::Share {
+                file,
+                password,
+                reference,
+            } => (file, matches!(password, Password::Requested), reference),
+            _ => panic!("not a share command"),
+        }
+    }
+
+    #[test]
+    fn share_options_in_any_order() {
+        let expected = ("my file.txt".to_string(), true, true);
+        assert_eq!(share("#share --ref --password my file.txt"), expected);
+        assert_eq!(share("#share --password --ref my file.txt"), expected);
+        assert_eq!(
+            share("#s --ref my file.txt"),
+            ("my file.txt".to_string(), false, true)
+        );
+        assert!("#share --ref".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn download_options_after_ticket() {
+        let Command::Download {
+            ticket,
+            output_file,
+            password,
+            force,
+            priority,
+        } = "#download --high ticket --force --password out file"
+            .parse()
+            .unwrap()
+        else {
+            panic!("not a download command");
+        };
+        assert_eq!(ticket, "ticket");
+        assert_eq!(output_file.as_deref(), Some("out file"));
+        assert!(matches!(password, Password::Requested));
+        assert!(force);
+        assert_eq!(priority, Priority::High);
+
+        let Command::Download {
+            output_file, force, ..
+        } = "#d ticket --force".parse().unwrap()
+        else {
+            panic!("not a download command");
+        };
+        assert_eq!(output_file, None);
+        assert!(force);
+    }
+
+    #[test]
+    fn download_refuses_option_like_output_name() {
+        assert!("#d ticket --forse".parse::<Command>().is_err());
+        assert!("#d --force".parse::<Command>().is_err());
+    }
+}
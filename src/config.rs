@@ -0,0 +1,382 @@
+//! Configuration files
+//!
+//! Configuration is read from `<data-dir>/config.toml` and `<data-dir>/<identity>.toml`,
+//! identity file overrides data directory file and command line arguments override both.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use iroh::{EndpointAddr, EndpointId, RelayUrl};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::{Args, identity};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoDownload {
+    /// Download only on request
+    #[default]
+    Never,
+    /// Automatically download files shared by trusted peers
+    Trusted,
+    /// Automatically download all shared files
+    All,
+}
+
+/// How downloaded files are exported from store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportMode {
+    /// Copy data, as copy-on-write clone where filesystem supports it
+    #[default]
+    Copy,
+    /// Move data out of store to downloaded file, store then keeps serving it from there
+    ///
+    /// Only used when set explicitly. Downloaded file must not be modified or deleted while it
+    /// is in store, otherwise store serves broken data. Small blobs are always copied.
+    Reference,
+}
+
+/// Static peer address in form `<endpoint-id>@<ip:port>`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PeerAddr(pub EndpointAddr);
+
+impl FromStr for PeerAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, addr) = s
+            .split_once('@')
+            .ok_or_else(|| anyhow!("Peer must be in form <endpoint-id>@<ip:port>"))?;
+        let id: EndpointId = id.parse().context("Invalid endpoint id")?;
+        let addr: SocketAddr = addr.parse().context("Invalid socket address")?;
+        Ok(PeerAddr(EndpointAddr::new(id).with_ip_addr(addr)))
+    }
+}
+
+impl TryFrom<String> for PeerAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Reads peers file, one peer per line, empty lines and lines starting with `#` are ignored
+pub async fn load_peers_file(file: &Path) -> Result<Vec<PeerAddr>> {
+    let content = fs::read_to_string(file)
+        .await
+        .with_context(|| format!("Cannot read peers file {}", file.display()))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse().with_context(|| format!("Invalid peer {line}")))
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub display_name: Option<String>,
+    pub disable_relays: Option<bool>,
+    /// Custom relay servers, replacing default public relays
+    pub relays: Option<Vec<RelayUrl>>,
+    pub bind: Option<Vec<SocketAddr>>,
+    pub peers: Option<Vec<PeerAddr>>,
+    pub peers_file: Option<PathBuf>,
+    /// Join timeout in seconds
+    pub join_timeout: Option<u64>,
+    pub disable_mdns: Option<bool>,
+    pub enable_dht: Option<bool>,
+    pub downloads_dir: Option<PathBuf>,
+    /// GC interval in seconds
+    pub gc_interval: Option<u64>,
+    /// Store size limit in MiB
+    pub store_quota: Option<u64>,
+    pub reference_imports: Option<bool>,
+    pub auto_download: Option<AutoDownload>,
+    pub export_mode: Option<ExportMode>,
+    pub preserve_attributes: Option<bool>,
+    /// Bandwidth limits in KiB/s
+    pub upload_limit: Option<u64>,
+    pub upload_limit_per_peer: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub download_limit_per_peer: Option<u64>,
+    pub parallel_downloads: Option<usize>,
+    pub watch_dir: Option<PathBuf>,
+    /// Watch debounce in seconds
+    pub watch_debounce: Option<u64>,
+    /// Endpoint ids of trusted peers
+    ///
+    /// Names are not accepted, any peer can introduce itself with any name.
+    pub trusted_peers: Option<Vec<EndpointId>>,
+}
+
+impl Config {
+    /// Values from `other` take precedence
+    fn merge(self, other: Config) -> Config {
+        Config {
+            display_name: other.display_name.or(self.display_name),
+            disable_relays: other.disable_relays.or(self.disable_relays),
+            relays: other.relays.or(self.relays),
+            bind: other.bind.or(self.bind),
+            peers: other.peers.or(self.peers),
+            peers_file: other.peers_file.or(self.peers_file),
+            join_timeout: other.join_timeout.or(self.join_timeout),
+            disable_mdns: other.disable_mdns.or(self.disable_mdns),
+            enable_dht: other.enable_dht.or(self.enable_dht),
+            downloads_dir: other.downloads_dir.or(self.downloads_dir),
+            gc_interval: other.gc_interval.or(self.gc_interval),
+            store_quota: other.store_quota.or(self.store_quota),
+            reference_imports: other.reference_imports.or(self.reference_imports),
+            auto_download: other.auto_download.or(self.auto_download),
+            export_mode: other.export_mode.or(self.export_mode),
+            preserve_attributes: other.preserve_attributes.or(self.preserve_attributes),
+            upload_limit: other.upload_limit.or(self.upload_limit),
+            upload_limit_per_peer: other.upload_limit_per_peer.or(self.upload_limit_per_peer),
+            download_limit: other.download_limit.or(self.download_limit),
+            download_limit_per_peer: other
+                .download_limit_per_peer
+                .or(self.download_limit_per_peer),
+            parallel_downloads: other.parallel_downloads.or(self.parallel_downloads),
+            watch_dir: other.watch_dir.or(self.watch_dir),
+            watch_debounce: other.watch_debounce.or(self.watch_debounce),
+            trusted_peers: other.trusted_peers.or(self.trusted_peers),
+        }
+    }
+}
+
+impl Config {
+    /// Makes relative paths relative to directory of config file, not to working directory
+    fn resolve_paths(mut self, dir: &Path) -> Config {
+        for path in [
+            &mut self.downloads_dir,
+            &mut self.peers_file,
+            &mut self.watch_dir,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+        self
+    }
+}
+
+async fn load_file(file: &Path) -> Result<Config> {
+    if fs::try_exists(file).await? {
+        let content = fs::read_to_string(file).await?;
+        toml::from_str(&content).with_context(|| format!("Invalid config file {}", file.display()))
+    } else {
+        Ok(Config::default())
+    }
+}
+
+pub async fn load_config(data_dir: &Path, identity: Option<&str>) -> Result<Config> {
+    let dir_config = load_file(&data_dir.join("config.toml")).await?;
+    let config = match identity {
+        Some(identity) => {
+            // name comes from command line, it must not point outside data directory
+            identity::check_name(identity)?;
+            let identity_config = load_file(&identity::config_file(data_dir, identity)).await?;
+            dir_config.merge(identity_config)
+        }
+        None => dir_config,
+    };
+    // both config files are in data directory
+    Ok(config.resolve_paths(data_dir))
+}
+
+/// Resolves boolean option from its command line flags (`on`, `off`) and config value
+fn flag(on: bool, off: bool, config: Option<bool>) -> bool {
+    on || (!off && config.unwrap_or(false))
+}
+
+impl Args {
+    /// Fills values not given on command line from config
+    ///
+    /// Boolean options have flags for both values, so command line overrides config either way.
+    pub fn apply_config(&mut self, config: Config) {
+        self.display_name = self.display_name.take().or(config.display_name);
+        self.disable_relays = flag(
+            self.disable_relays,
+            self.enable_relays,
+            config.disable_relays,
+        );
+        if self.relays.is_empty() {
+            self.relays = config.relays.unwrap_or_default();
+        }
+        if self.bind.is_empty() {
+            self.bind = config.bind.unwrap_or_default();
+        }
+        if self.peers.is_empty() {
+            self.peers = config.peers.unwrap_or_default();
+        }
+        self.peers_file = self.peers_file.take().or(config.peers_file);
+        self.join_timeout = self.join_timeout.or(config.join_timeout);
+        self.disable_mdns = flag(self.disable_mdns, self.enable_mdns, config.disable_mdns);
+        self.enable_dht = flag(self.enable_dht, self.disable_dht, config.enable_dht);
+        self.downloads_dir = self.downloads_dir.take().or(config.downloads_dir);
+        self.gc_interval = self.gc_interval.or(config.gc_interval);
+        self.store_quota = self.store_quota.or(config.store_quota);
+        self.reference_imports = flag(
+            self.reference_imports,
+            self.no_reference_imports,
+            config.reference_imports,
+        );
+        self.auto_download = self.auto_download.or(config.auto_download);
+        self.export_mode = self.export_mode.or(config.export_mode);
+        self.preserve_attributes = flag(
+            self.preserve_attributes,
+            self.no_preserve_attributes,
+            config.preserve_attributes,
+        );
+        self.upload_limit = self.upload_limit.or(config.upload_limit);
+        self.upload_limit_per_peer = self.upload_limit_per_peer.or(config.upload_limit_per_peer);
+        self.download_limit = self.download_limit.or(config.download_limit);
+        self.download_limit_per_peer = self
+            .download_limit_per_peer
+            .or(config.download_limit_per_peer);
+        self.parallel_downloads = self.parallel_downloads.or(config.parallel_downloads);
+        self.watch_dir = self.watch_dir.take().or(config.watch_dir);
+        self.watch_debounce = self.watch_debounce.or(config.watch_debounce);
+        if self.trusted_peers.is_empty() {
+            self.trusted_peers = config.trusted_peers.unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn parse_args(cli: &[&str]) -> Args {
+        Args::try_parse_from(["send-file"].iter().chain(cli).chain(&["start"])).unwrap()
+    }
+
+    fn config(content: &str) -> Config {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn flag_prefers_command_line() {
+        assert!(!flag(false, false, None));
+        assert!(flag(false, false, Some(true)));
+        assert!(!flag(false, true, Some(true)));
+        assert!(flag(true, false, Some(false)));
+        assert!(flag(true, false, None));
+    }
+
+    #[test]
+    fn identity_config_overrides_dir_config() {
+        let dir = config("gc_interval = 10\nstore_quota = 100\nenable_dht = true");
+        let identity = config("gc_interval = 20\nenable_dht = false");
+        let merged = dir.merge(identity);
+        assert_eq!(merged.gc_interval, Some(20));
+        assert_eq!(merged.store_quota, Some(100));
+        assert_eq!(merged.enable_dht, Some(false));
+    }
+
+    #[tokio::test]
+    async fn identity_config_outside_data_dir_is_refused() {
+        let dir = TestDir::new();
+        assert!(load_config(dir.root(), Some("../x")).await.is_err());
+        assert!(load_config(dir.root(), Some("alice")).await.is_ok());
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let dir = config("gc_interval = 10\nstore_quota = 100\ndisplay_name = \"dir\"");
+        let identity = config("gc_interval = 20\ndisplay_name = \"identity\"");
+        let mut args = parse_args(&["--gc-interval", "30"]);
+        args.apply_config(dir.merge(identity));
+        assert_eq!(args.gc_interval, Some(30));
+        assert_eq!(args.store_quota, Some(100));
+        assert_eq!(args.display_name.as_deref(), Some("identity"));
+    }
+
+    #[test]
+    fn negative_flags_override_config() {
+        let enabled = || {
+            config(
+                "disable_relays = true\ndisable_mdns = true\nenable_dht = true\n\
+                 reference_imports = true\npreserve_attributes = true",
+            )
+        };
+        let mut args = parse_args(&[]);
+        args.apply_config(enabled());
+        assert!(args.disable_relays && args.disable_mdns && args.enable_dht);
+        assert!(args.reference_imports && args.preserve_attributes);
+
+        let mut args = parse_args(&[
+            "--enable-relays",
+            "--enable-mdns",
+            "--disable-dht",
+            "--no-reference-imports",
+            "--no-preserve-attributes",
+        ]);
+        args.apply_config(enabled());
+        assert!(!args.disable_relays && !args.disable_mdns && !args.enable_dht);
+        assert!(!args.reference_imports && !args.preserve_attributes);
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_data_dir() {
+        let config = config(
+            "downloads_dir = \"down\"\npeers_file = \"peers.txt\"\nwatch_dir = \"/abs/watch\"",
+        )
+        .resolve_paths(Path::new("/data"));
+        assert_eq!(config.downloads_dir, Some(PathBuf::from("/data/down")));
+        assert_eq!(config.peers_file, Some(PathBuf::from("/data/peers.txt")));
+        assert_eq!(config.watch_dir, Some(PathBuf::from("/abs/watch")));
+    }
+
+    #[test]
+    fn peer_address_needs_endpoint_id_and_socket_address() {
+        let id = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let peer: PeerAddr = format!("{id}@127.0.0.1:4000").parse().unwrap();
+        assert_eq!(peer.0.id, id);
+        assert_eq!(
+            peer.0.ip_addrs().collect::<Vec<_>>(),
+            [&"127.0.0.1:4000".parse::<SocketAddr>().unwrap()]
+        );
+        assert!("127.0.0.1:4000".parse::<PeerAddr>().is_err());
+        assert!("nonsense@127.0.0.1:4000".parse::<PeerAddr>().is_err());
+        assert!(format!("{id}@127.0.0.1").parse::<PeerAddr>().is_err());
+        assert!(format!("{id}@localhost:4000").parse::<PeerAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn peers_file_skips_comments_and_blank_lines() {
+        let dir = TestDir::new();
+        let (a, b) = (
+            iroh::SecretKey::generate(&mut rand::rng()).public(),
+            iroh::SecretKey::generate(&mut rand::rng()).public(),
+        );
+        let file = dir.path("peers.txt");
+        std::fs::write(
+            &file,
+            format!("# office\n{a}@10.0.0.1:4000\n\n   \n  {b}@[::1]:4001  \n"),
+        )
+        .unwrap();
+        let peers = load_peers_file(&file).await.unwrap();
+        let ids: Vec<_> = peers.iter().map(|peer| peer.0.id).collect();
+        assert_eq!(ids, [a, b]);
+
+        std::fs::write(&file, format!("{a}@10.0.0.1:4000\n{b}@10.0.0.2\n")).unwrap();
+        assert!(load_peers_file(&file).await.is_err());
+        assert!(load_peers_file(&dir.path("missing.txt")).await.is_err());
+    }
+}
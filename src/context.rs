@@ -1,7 +1,8 @@
 #![allow(dead_code)]
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
     time::Duration,
@@ -9,20 +10,32 @@ use std::{
 
 use anyhow::Result;
 use iroh::{
-    Endpoint, EndpointAddr, PublicKey, RelayMode, SecretKey, discovery::mdns::MdnsDiscovery,
+    Endpoint, EndpointAddr, PublicKey, RelayMode, SecretKey,
+    discovery::{mdns::MdnsDiscovery, static_provider::StaticProvider},
 };
-use iroh_blobs::store::{
-    GcConfig,
-    fs::{
-        FsStore,
-        options::{BatchOptions, InlineOptions, Options, PathOptions},
+use iroh_blobs::{
+    Hash,
+    store::{
+        GcConfig,
+        fs::{
+            FsStore,
+            options::{BatchOptions, InlineOptions, Options, PathOptions},
+        },
     },
 };
 use iroh_gossip::TopicId;
-use tokio::fs;
 use tracing::error;
 
-use crate::{Args, channel::Message, command::Command};
+use crate::{
+    Args,
+    channel::{FileInfo, KeyHandover, Message},
+    command::Command,
+    config::{AutoDownload, PeerAddr, load_peers_file},
+    identity::{load_handovers, load_identity, trim_handovers},
+    limits::Limits,
+    queue::{DEFAULT_PARALLEL_DOWNLOADS, DownloadQueue},
+    store::{AccessLog, References},
+};
 
 #[derive(Clone)]
 pub struct PeersDirectory {
@@ -49,19 +62,58 @@ impl PeersDirectory {
         self.inner.read().unwrap().find_by_name(name).cloned()
     }
 
+    /// Trusts peer for automatic downloads
+    pub fn trust(&self, public_key: PublicKey) {
+        self.inner.write().unwrap().trusted.insert(public_key);
+    }
+
+    pub fn is_trusted(&self, public_key: &PublicKey) -> bool {
+        self.inner.read().unwrap().trusted.contains(public_key)
+    }
+
+    /// Transfers name and trust of old key to new key, returns transferred name if old key was known
+    ///
+    /// Handover must be verified before.
+    pub fn rotate_key(&self, old: &PublicKey, new: PublicKey) -> Option<Arc<str>> {
+        self.inner.write().unwrap().rotate_key(old, new)
+    }
+
+    pub fn neighbor_up(&self, public_key: PublicKey) {
+        self.inner.write().unwrap().neighbors.insert(public_key);
+    }
+
+    pub fn neighbor_down(&self, public_key: &PublicKey) {
+        self.inner.write().unwrap().neighbors.remove(public_key);
+    }
+
+    pub fn neighbors(&self) -> Vec<PublicKey> {
+        self.inner
+            .read()
+            .unwrap()
+            .neighbors
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Ids of all peers which introduced themselves
+    pub fn known_peers(&self) -> Vec<PublicKey> {
+        self.inner.read().unwrap().peers.keys().cloned().collect()
+    }
+
     pub fn friendly_name(&self, id: &PublicKey) -> String {
         let short_id = id.fmt_short().to_string();
-        let name = self
-            .find_by_id(&id)
+        self.find_by_id(id)
             .map(|name| format!("[{name}:{short_id}]"))
-            .unwrap_or_else(|| format!("[{short_id}]"));
-        name
+            .unwrap_or_else(|| format!("[{short_id}]"))
     }
 }
 
 struct PeersDirectoryInner {
     peers: HashMap<PublicKey, Arc<str>>,
     names: HashMap<String, PublicKey>,
+    neighbors: HashSet<PublicKey>,
+    trusted: HashSet<PublicKey>,
 }
 
 impl PeersDirectoryInner {
@@ -69,6 +121,8 @@ impl PeersDirectoryInner {
         PeersDirectoryInner {
             peers: HashMap::new(),
             names: HashMap::new(),
+            neighbors: HashSet::new(),
+            trusted: HashSet::new(),
         }
     }
 
@@ -82,52 +136,90 @@ impl PeersDirectoryInner {
         self.peers.get(public_key).cloned()
     }
 
+    fn rotate_key(&mut self, old: &PublicKey, new: PublicKey) -> Option<Arc<str>> {
+        if self.trusted.remove(old) {
+            self.trusted.insert(new);
+        }
+        let name = self.peers.remove(old)?;
+        self.peers.insert(new, name.clone());
+        self.names.insert(name.to_string(), new);
+        Some(name)
+    }
+
     fn find_by_name(&self, name: &str) -> Option<&PublicKey> {
         self.names.get(name)
     }
 }
 
-async fn init_store(data_dir: &Path, identity: &str) -> Result<FsStore> {
-    let path = data_dir.join(format!("store-{}", identity));
+const DEFAULT_GC_INTERVAL: u64 = 60;
+
+pub fn gc_interval(args: &Args) -> Duration {
+    Duration::from_secs(args.gc_interval.unwrap_or(DEFAULT_GC_INTERVAL))
+}
+
+pub fn store_path(data_dir: &Path, identity: &str) -> PathBuf {
+    data_dir.join(format!("store-{}", identity))
+}
+
+pub async fn init_store(data_dir: &Path, identity: &str, gc_interval: Duration) -> Result<FsStore> {
+    let path = store_path(data_dir, identity);
     let options = Options {
         path: PathOptions::new(&path),
         inline: InlineOptions::default(),
         batch: BatchOptions::default(),
         gc: Some(GcConfig {
             add_protected: None,
-            interval: Duration::from_secs(60),
+            interval: gc_interval,
         }),
     };
     let store = FsStore::load_with_opts(path.join("store.db"), options).await?;
     Ok(store)
 }
 
-async fn load_identity(data_dir: &Path, name: &str) -> Result<SecretKey> {
-    let file = data_dir.join(name).with_extension("id");
-    if fs::try_exists(&file).await? {
-        let key_data = fs::read(file).await?;
-        if key_data.len() != 32 {
-            anyhow::bail!("Secret key must be 32 bytes");
-        }
-        let key_ref: &[u8; 32] = key_data[..].try_into()?;
-        Ok(SecretKey::from_bytes(key_ref))
+async fn init_endpoint(args: &Args, static_addrs: StaticProvider) -> Result<Endpoint> {
+    let secret_key = load_identity(&args.data_dir, args.identity()?, args.encrypt_identity).await?;
+    bind_endpoint(args, &args.bind, secret_key, static_addrs).await
+}
+
+pub fn relay_mode(args: &Args) -> RelayMode {
+    if args.disable_relays {
+        RelayMode::Disabled
+    } else if args.relays.is_empty() {
+        RelayMode::Default
     } else {
-        let key = SecretKey::generate(&mut rand::rng());
-        fs::write(&file, key.to_bytes()).await?;
-        Ok(key)
+        RelayMode::Custom(args.relays.iter().cloned().collect())
     }
 }
 
-async fn init_endpoint(args: &Args) -> Result<Endpoint> {
-    let secret_key = load_identity(&args.data_dir, &args.identity).await?;
-
+/// Binds endpoint with given key and local addresses, other network configuration is from arguments
+///
+/// Empty `bind` lets system pick ports.
+pub async fn bind_endpoint(
+    args: &Args,
+    bind: &[SocketAddr],
+    secret_key: SecretKey,
+    static_addrs: StaticProvider,
+) -> Result<Endpoint> {
     let mut builder = if args.disable_relays {
         Endpoint::empty_builder(RelayMode::Disabled)
     } else {
-        Endpoint::builder().relay_mode(RelayMode::Default)
+        Endpoint::builder().relay_mode(relay_mode(args))
     };
 
-    builder = builder.secret_key(secret_key.clone());
+    let v4_count = bind.iter().filter(|addr| addr.is_ipv4()).count();
+    if v4_count > 1 || bind.len() - v4_count > 1 {
+        anyhow::bail!("Only one IPv4 and one IPv6 bind address can be given");
+    }
+    for addr in bind {
+        builder = match addr {
+            SocketAddr::V4(addr) => builder.bind_addr_v4(*addr),
+            SocketAddr::V6(addr) => builder.bind_addr_v6(*addr),
+        };
+    }
+
+    builder = builder
+        .secret_key(secret_key.clone())
+        .discovery(static_addrs);
 
     if !args.disable_mdns {
         let mdns = MdnsDiscovery::builder();
@@ -155,24 +247,66 @@ impl Context {
         args: &Args,
         topic_id: TopicId,
         topic_endpoints: Vec<EndpointAddr>,
+        room: Option<String>,
         output_sender: tokio::sync::mpsc::Sender<String>,
         input_sender: tokio::sync::mpsc::Sender<Command>,
     ) -> Result<Self> {
+        let identity = args.identity()?;
         let peers = PeersDirectory::new();
-        let store = init_store(&args.data_dir, &args.identity).await?;
-
-        let endpoint = init_endpoint(&args).await?;
+        for id in &args.trusted_peers {
+            peers.trust(*id);
+        }
+        let store = init_store(&args.data_dir, identity, gc_interval(args)).await?;
 
+        let static_addrs = StaticProvider::from_endpoint_info(topic_endpoints.clone());
+        let mut static_peers = args.peers.clone();
+        if let Some(file) = args.peers_file.as_ref() {
+            static_peers.extend(load_peers_file(file).await?);
+        }
+        for PeerAddr(addr) in static_peers {
+            static_addrs.add_endpoint_info(addr);
+        }
+        let endpoint = init_endpoint(args, static_addrs).await?;
+
+        let mut handovers = load_handovers(&args.data_dir, identity).await?;
+        trim_handovers(&mut handovers, &endpoint.id());
+
+        let store_dir = store_path(&args.data_dir, identity);
+        let access_log = AccessLog::load(&store_dir).await?;
+        let references = References::load(&store_dir).await?;
+        let kib = |limit: Option<u64>| limit.unwrap_or(0).saturating_mul(1024);
+        let limits = Limits::new(
+            kib(args.upload_limit),
+            kib(args.upload_limit_per_peer),
+            kib(args.download_limit),
+            kib(args.download_limit_per_peer),
+        );
         let downloader = store.downloader(&endpoint);
         Ok(Context {
             inner: Arc::new(ContextInner {
                 peers,
                 store,
+                access_log,
+                references,
+                file_infos: RwLock::new(HashMap::new()),
+                limits,
+                downloads: DownloadQueue::new(
+                    args.parallel_downloads
+                        .unwrap_or(DEFAULT_PARALLEL_DOWNLOADS),
+                ),
                 downloader,
                 endpoint,
+                handovers,
                 topic_id,
                 topic_endpoints,
-                identity: args.identity.clone(),
+                room,
+                invite_ttl: args
+                    .invite_ttl
+                    .map(|mins| Duration::from_secs(mins.saturating_mul(60))),
+                show_qr: args.qr || args.qr_png,
+                qr_png: args.qr_png,
+                identity: identity.to_string(),
+                args: args.clone(),
                 data_dir: args.data_dir.clone(),
                 output_sender,
                 input_sender,
@@ -188,6 +322,28 @@ impl Context {
         &self.inner.store
     }
 
+    pub fn store_path(&self) -> PathBuf {
+        store_path(self.data_dir(), self.identity())
+    }
+
+    /// Last access times of downloaded blobs
+    pub fn access_log(&self) -> &AccessLog {
+        &self.inner.access_log
+    }
+
+    /// Files shared by reference
+    pub fn references(&self) -> &References {
+        &self.inner.references
+    }
+
+    /// Store size limit in bytes
+    pub fn store_quota(&self) -> Option<u64> {
+        self.inner
+            .args
+            .store_quota
+            .map(|mib| mib.saturating_mul(1024 * 1024))
+    }
+
     pub fn downloader(&self) -> &iroh_blobs::api::downloader::Downloader {
         &self.inner.downloader
     }
@@ -196,6 +352,11 @@ impl Context {
         &self.inner.endpoint
     }
 
+    /// Key handovers of our identity, to be announced to peers
+    pub fn handovers(&self) -> &[KeyHandover] {
+        &self.inner.handovers
+    }
+
     pub fn topic_id(&self) -> &TopicId {
         &self.inner.topic_id
     }
@@ -204,10 +365,87 @@ impl Context {
         &self.inner.topic_endpoints
     }
 
+    pub fn room(&self) -> Option<&str> {
+        self.inner.room.as_deref()
+    }
+
+    pub fn invite_ttl(&self) -> Option<Duration> {
+        self.inner.invite_ttl
+    }
+
+    pub fn show_qr(&self) -> bool {
+        self.inner.show_qr
+    }
+
+    pub fn qr_png(&self) -> bool {
+        self.inner.qr_png
+    }
+
     pub fn identity(&self) -> &str {
         &self.inner.identity
     }
 
+    /// Name announced to other users
+    pub fn display_name(&self) -> &str {
+        self.inner
+            .args
+            .display_name
+            .as_deref()
+            .unwrap_or(&self.inner.identity)
+    }
+
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.inner
+            .args
+            .downloads_dir
+            .clone()
+            .unwrap_or_else(|| self.inner.data_dir.join("downloads"))
+    }
+
+    pub fn downloads(&self) -> &DownloadQueue {
+        &self.inner.downloads
+    }
+
+    /// Bandwidth limits
+    pub fn limits(&self) -> &Limits {
+        &self.inner.limits
+    }
+
+    /// Remembers metadata of file shared in channel
+    ///
+    /// Metadata announced first is kept, other peers cannot replace it for the same hash.
+    pub fn add_file_info(&self, hash: Hash, info: FileInfo, from: PublicKey) {
+        let mut infos = self.inner.file_infos.write().unwrap();
+        match infos.get(&hash) {
+            Some((sender, _)) if *sender != from => {}
+            _ => {
+                infos.insert(hash, (from, info));
+            }
+        }
+    }
+
+    pub fn file_info(&self, hash: &Hash) -> Option<FileInfo> {
+        self.inner
+            .file_infos
+            .read()
+            .unwrap()
+            .get(hash)
+            .map(|(_, info)| info.clone())
+    }
+
+    /// Checks auto download policy for files shared by peer
+    pub fn should_auto_download(&self, peer: &PublicKey) -> bool {
+        match self.inner.args.auto_download.unwrap_or_default() {
+            AutoDownload::Never => false,
+            AutoDownload::All => true,
+            AutoDownload::Trusted => self.inner.peers.is_trusted(peer),
+        }
+    }
+
+    pub fn args(&self) -> &Args {
+        &self.inner.args
+    }
+
     pub fn data_dir(&self) -> &Path {
         self.inner.data_dir.as_path()
     }
@@ -242,12 +480,60 @@ impl Context {
 struct ContextInner {
     peers: PeersDirectory,
     store: FsStore,
+    access_log: AccessLog,
+    references: References,
+    /// Metadata of shared files with peer which announced them
+    file_infos: RwLock<HashMap<Hash, (PublicKey, FileInfo)>>,
+    limits: Limits,
+    downloads: DownloadQueue,
     downloader: iroh_blobs::api::downloader::Downloader,
     endpoint: Endpoint,
+    handovers: Vec<KeyHandover>,
     topic_id: TopicId,
     topic_endpoints: Vec<EndpointAddr>,
+    room: Option<String>,
+    invite_ttl: Option<Duration>,
+    show_qr: bool,
+    qr_png: bool,
     identity: String,
+    args: Args,
     data_dir: PathBuf,
     output_sender: tokio::sync::mpsc::Sender<String>,
     input_sender: tokio::sync::mpsc::Sender<Command>,
 }
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn rotated_key_keeps_name_and_trust() {
+        let (old, new) = (
+            SecretKey::generate(&mut rand::rng()).public(),
+            SecretKey::generate(&mut rand::rng()).public(),
+        );
+        let peers = PeersDirectory::new();
+        peers.add_peer(old, "alice".to_string());
+        peers.trust(old);
+
+        assert_eq!(peers.rotate_key(&old, new).as_deref(), Some("alice"));
+        assert_eq!(peers.find_by_id(&new).as_deref(), Some("alice"));
+        assert_eq!(peers.find_by_name("alice"), Some(new));
+        assert!(peers.find_by_id(&old).is_none());
+        assert!(peers.is_trusted(&new));
+        assert!(!peers.is_trusted(&old));
+    }
+
+    #[test]
+    fn unknown_key_rotation_gives_nothing() {
+        let (old, new) = (
+            SecretKey::generate(&mut rand::rng()).public(),
+            SecretKey::generate(&mut rand::rng()).public(),
+        );
+        let peers = PeersDirectory::new();
+        assert!(peers.rotate_key(&old, new).is_none());
+        assert!(!peers.is_trusted(&new));
+    }
+}
@@ -0,0 +1,276 @@
+//! Password based encryption of shared files
+//!
+//! Key is derived from password with Argon2id, content is encrypted with ChaCha20Poly1305
+//! in STREAM construction (chunks of [`CHUNK_SIZE`]), so large files need not fit in memory.
+//! Encrypted file starts with header: magic, salt and nonce prefix.
+//! Small secrets (like identity keys) are sealed in one piece with [`seal`].
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{
+        Aead,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+
+const MAGIC: &[u8; 6] = b"SFENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+pub const MAGIC_LEN: usize = MAGIC.len();
+pub const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+pub fn derive_key(password: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    argon2::Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {e}"))?;
+    Ok(key)
+}
+
+const SEAL_NONCE_LEN: usize = 12;
+
+/// Encrypts small data with password, result contains salt and nonce
+pub fn seal(password: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; SEAL_NONCE_LEN] = rand::random();
+    let key = derive_key(password, &salt)?;
+    let encrypted = ChaCha20Poly1305::new(&key)
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    let mut sealed = Vec::with_capacity(SALT_LEN + SEAL_NONCE_LEN + encrypted.len());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend(encrypted);
+    Ok(sealed)
+}
+
+/// Decrypts data sealed by [`seal`]
+pub fn open(password: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < SALT_LEN + SEAL_NONCE_LEN + TAG_LEN {
+        anyhow::bail!("Sealed data too short");
+    }
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, encrypted) = rest.split_at(SEAL_NONCE_LEN);
+    let key = derive_key(password, salt)?;
+    ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .map_err(|_| anyhow!("Wrong password or corrupted data"))
+}
+
+/// Reads until buffer is full or EOF, returns number of bytes read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Checks if data start with encryption header
+pub fn has_header(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Checks if file of given length can be encrypted file, every chunk has authentication tag
+///
+/// Together with header it distinguishes encrypted files from plain files starting with magic.
+pub fn is_encrypted_len(len: u64) -> bool {
+    let Some(body) = len.checked_sub(HEADER_LEN as u64) else {
+        return false;
+    };
+    let (chunk, tag) = ((CHUNK_SIZE + TAG_LEN) as u64, TAG_LEN as u64);
+    body >= tag && (body % chunk == 0 || body % chunk >= tag)
+}
+
+pub fn encrypt_file(input: &Path, output: &Path, password: &str) -> Result<()> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let key = derive_key(password, &salt)?;
+    let mut encryptor = EncryptorBE32::from_aead(ChaCha20Poly1305::new(&key), &nonce.into());
+
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+    writer.write_all(&nonce)?;
+
+    // one chunk look ahead, because last chunk must be encrypted differently
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut len = read_full(&mut reader, &mut chunk)?;
+    loop {
+        let next_len = if len == CHUNK_SIZE {
+            read_full(&mut reader, &mut next)?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let data = encryptor
+                .encrypt_last(&chunk[..len])
+                .map_err(|_| anyhow!("Encryption failed"))?;
+            writer.write_all(&data)?;
+            break;
+        }
+        let data = encryptor
+            .encrypt_next(&chunk[..len])
+            .map_err(|_| anyhow!("Encryption failed"))?;
+        writer.write_all(&data)?;
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn decrypt_file(input: &Path, output: &Path, password: &str) -> Result<()> {
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut header = [0u8; HEADER_LEN];
+    if read_full(&mut reader, &mut header)? != HEADER_LEN || !header.starts_with(MAGIC) {
+        anyhow::bail!("File is not encrypted");
+    }
+    let salt = &header[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce: [u8; NONCE_LEN] = header[MAGIC.len() + SALT_LEN..].try_into()?;
+    let key = derive_key(password, salt)?;
+    let mut decryptor = DecryptorBE32::from_aead(ChaCha20Poly1305::new(&key), &nonce.into());
+
+    let res = (|| {
+        let mut writer = BufWriter::new(File::create(output)?);
+        let mut chunk = vec![0u8; CHUNK_SIZE + TAG_LEN];
+        let mut next = vec![0u8; CHUNK_SIZE + TAG_LEN];
+        let mut len = read_full(&mut reader, &mut chunk)?;
+        loop {
+            let next_len = if len == chunk.len() {
+                read_full(&mut reader, &mut next)?
+            } else {
+                0
+            };
+            if next_len == 0 {
+                let data = decryptor
+                    .decrypt_last(&chunk[..len])
+                    .map_err(|_| anyhow!("Wrong password or corrupted file"))?;
+                writer.write_all(&data)?;
+                break;
+            }
+            let data = decryptor
+                .decrypt_next(&chunk[..len])
+                .map_err(|_| anyhow!("Wrong password or corrupted file"))?;
+            writer.write_all(&data)?;
+            std::mem::swap(&mut chunk, &mut next);
+            len = next_len;
+        }
+        writer.flush().context("Cannot write output")
+    })();
+    if res.is_err() {
+        std::fs::remove_file(output).ok();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn file_round_trip_across_chunk_boundaries() {
+        let dir = TestDir::new();
+        let (plain, encrypted, decrypted) = (
+            dir.path("plain"),
+            dir.path("encrypted"),
+            dir.path("decrypted"),
+        );
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+            2 * CHUNK_SIZE + 1,
+        ] {
+            let data = test_data(len);
+            std::fs::write(&plain, &data).unwrap();
+            encrypt_file(&plain, &encrypted, "secret").unwrap();
+            let encrypted_data = std::fs::read(&encrypted).unwrap();
+            assert!(has_header(&encrypted_data));
+            assert!(is_encrypted_len(encrypted_data.len() as u64));
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(encrypted_data.len(), HEADER_LEN + len + chunks * TAG_LEN);
+            decrypt_file(&encrypted, &decrypted, "secret").unwrap();
+            assert_eq!(std::fs::read(&decrypted).unwrap(), data, "length {len}");
+        }
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let dir = TestDir::new();
+        let (plain, encrypted, decrypted) = (
+            dir.path("plain"),
+            dir.path("encrypted"),
+            dir.path("decrypted"),
+        );
+        std::fs::write(&plain, test_data(CHUNK_SIZE + 1)).unwrap();
+        encrypt_file(&plain, &encrypted, "secret").unwrap();
+        let err = decrypt_file(&encrypted, &decrypted, "wrong").unwrap_err();
+        assert!(err.to_string().contains("Wrong password"));
+        assert!(!decrypted.exists(), "partial output is removed");
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let dir = TestDir::new();
+        let (plain, encrypted, decrypted) = (
+            dir.path("plain"),
+            dir.path("encrypted"),
+            dir.path("decrypted"),
+        );
+        std::fs::write(&plain, test_data(2 * CHUNK_SIZE + 1)).unwrap();
+        encrypt_file(&plain, &encrypted, "secret").unwrap();
+        let data = std::fs::read(&encrypted).unwrap();
+        // drop last chunk, remaining chunks must not pass as complete file
+        std::fs::write(&encrypted, &data[..HEADER_LEN + 2 * (CHUNK_SIZE + TAG_LEN)]).unwrap();
+        assert!(decrypt_file(&encrypted, &decrypted, "secret").is_err());
+        assert!(!decrypted.exists());
+    }
+
+    #[test]
+    fn inconsistent_lengths_are_not_encrypted() {
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        for len in [
+            0,
+            MAGIC_LEN,
+            HEADER_LEN,
+            HEADER_LEN + TAG_LEN - 1,
+            HEADER_LEN + chunk + 1,
+            HEADER_LEN + 2 * chunk + TAG_LEN - 1,
+        ] {
+            assert!(!is_encrypted_len(len as u64), "length {len}");
+        }
+    }
+
+    #[test]
+    fn plain_file_is_not_decrypted() {
+        let dir = TestDir::new();
+        let (plain, decrypted) = (dir.path("plain"), dir.path("decrypted"));
+        std::fs::write(&plain, test_data(100)).unwrap();
+        let err = decrypt_file(&plain, &decrypted, "secret").unwrap_err();
+        assert!(err.to_string().contains("not encrypted"));
+    }
+}
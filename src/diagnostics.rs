@@ -0,0 +1,181 @@
+//! Network diagnostics, used by `#net` command and `doctor` subcommand
+
+use std::time::Duration;
+
+use anyhow::Result;
+use iroh::{
+    Endpoint, EndpointId, RelayMap, RelayMode, RelayUrl, SecretKey, Watcher as _,
+    discovery::static_provider::StaticProvider, endpoint::ConnectionType,
+};
+
+use crate::{
+    Args,
+    context::{bind_endpoint, relay_mode},
+};
+
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn yes_no(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+/// Creates report lines about endpoint and connections to given peers (with friendly names)
+///
+/// Endpoint ID is not included, caller knows whether endpoint has identity's key.
+pub fn network_report(
+    args: &Args,
+    endpoint: &Endpoint,
+    peers: &[(String, EndpointId)],
+) -> Vec<String> {
+    let mut lines = vec![];
+
+    let sockets: Vec<_> = endpoint
+        .bound_sockets()
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect();
+    lines.push(format!("Bound sockets: {}", sockets.join(", ")));
+
+    let relay_urls = |map: RelayMap| {
+        let urls: Vec<RelayUrl> = map.urls();
+        let urls: Vec<_> = urls.iter().map(|url| url.to_string()).collect();
+        urls.join(", ")
+    };
+    match relay_mode(args) {
+        RelayMode::Disabled => lines.push("Relays: disabled".into()),
+        RelayMode::Custom(map) => lines.push(format!("Relays (custom): {}", relay_urls(map))),
+        mode => lines.push(format!("Relays (public): {}", relay_urls(mode.relay_map()))),
+    }
+    let addr = endpoint.addr();
+    let home_relay = addr
+        .relay_urls()
+        .map(|url| url.to_string())
+        .next()
+        .unwrap_or_else(|| "none".into());
+    lines.push(format!("Home relay: {home_relay}"));
+    let direct: Vec<_> = addr.ip_addrs().map(|addr| addr.to_string()).collect();
+    lines.push(format!(
+        "Direct addresses: {}",
+        if direct.is_empty() {
+            "none".to_string()
+        } else {
+            direct.join(", ")
+        }
+    ));
+
+    match endpoint.net_report().get() {
+        Some(report) => {
+            lines.push(format!(
+                "UDP: IPv4 {}, IPv6 {}",
+                yes_no(Some(report.udp_v4)),
+                yes_no(Some(report.udp_v6))
+            ));
+            let global: Vec<_> = report
+                .global_v4
+                .map(|addr| addr.to_string())
+                .into_iter()
+                .chain(report.global_v6.map(|addr| addr.to_string()))
+                .collect();
+            lines.push(format!(
+                "Public addresses: {}",
+                if global.is_empty() {
+                    "unknown".to_string()
+                } else {
+                    global.join(", ")
+                }
+            ));
+            let nat = match report.mapping_varies_by_dest() {
+                Some(true) => "mapping varies by destination (hard NAT, relay likely needed)",
+                Some(false) => "stable mapping (direct connections should work)",
+                None => "unknown",
+            };
+            lines.push(format!("NAT: {nat}"));
+            lines.push(format!("Captive portal: {}", yes_no(report.captive_portal)));
+            if let Some(relay) = report.preferred_relay.as_ref() {
+                lines.push(format!("Preferred relay: {relay}"));
+            }
+        }
+        None => lines.push("NAT: unknown (network report not available yet)".into()),
+    }
+
+    if peers.is_empty() {
+        lines.push("Peers: no connected peers".into());
+    }
+    for (name, id) in peers {
+        let path = endpoint
+            .conn_type(*id)
+            .map(|mut conn| conn.get().to_string())
+            .unwrap_or_else(|| "no address info".into());
+        let latency = endpoint
+            .latency(*id)
+            .map(|latency| format!(", latency {} ms", latency.as_millis()))
+            .unwrap_or_default();
+        lines.push(format!("Peer {name}: {path}{latency}"));
+    }
+    lines
+}
+
+/// Explains why we cannot join channel through bootstrap endpoints
+pub fn join_status(args: &Args, endpoint: &Endpoint, bootstrap: &[EndpointId]) -> Vec<String> {
+    let mut lines = vec![];
+    if !args.disable_relays && endpoint.addr().relay_urls().next().is_none() {
+        lines.push(
+            "Relay: not connected to any relay server (check internet connection or --relay)"
+                .into(),
+        );
+    }
+    for id in bootstrap {
+        let short_id = id.fmt_short();
+        let line = match endpoint.conn_type(*id).map(|mut conn| conn.get()) {
+            None => format!(
+                "Peer {short_id}: no address found - discovery failing (peer offline, or try --peer)"
+            ),
+            Some(ConnectionType::None) => format!(
+                "Peer {short_id}: address known, but no path works - connection handshake failing"
+            ),
+            Some(path) => {
+                format!("Peer {short_id}: trying {path}, no gossip handshake yet (peer offline?)")
+            }
+        };
+        lines.push(line);
+    }
+    lines
+}
+
+/// Binds temporary endpoint with current network configuration and prints report
+pub async fn doctor(args: &Args) -> Result<()> {
+    let secret_key = SecretKey::generate(&mut rand::rng());
+    let endpoint = bind_endpoint(args, &args.bind, secret_key, StaticProvider::new()).await?;
+    println!(
+        "Checking network, this can take up to {} s",
+        DOCTOR_TIMEOUT.as_secs()
+    );
+    let checks = async {
+        if !args.disable_relays {
+            endpoint.online().await;
+        }
+        let mut report = endpoint.net_report();
+        while report.get().is_none() {
+            if report.updated().await.is_err() {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout(DOCTOR_TIMEOUT, checks).await.is_err() {
+        println!("Warning: endpoint did not come online in time (relay not reachable?)");
+    }
+    // identity key may be encrypted, so doctor does not need passphrase
+    println!(
+        "Endpoint ID: {} (temporary diagnostic endpoint, not identity's)",
+        endpoint.id()
+    );
+    for line in network_report(args, &endpoint, &[]) {
+        println!("{line}");
+    }
+    endpoint.close().await;
+    Ok(())
+}
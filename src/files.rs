@@ -0,0 +1,304 @@
+//! Output file handling for downloads
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use iroh_blobs::Hash;
+use tokio::{fs, io::AsyncWriteExt as _};
+
+use crate::channel::FileInfo;
+
+const MAX_NAME_LEN: usize = 255;
+
+/// Makes file name received from peer safe to use in downloads directory
+///
+/// Path separators, control and reserved characters are replaced, leading dots removed
+/// (no hidden files or `..`), Windows device names are escaped. Returns `None` if nothing usable remains.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let mut name = name
+        .trim_start_matches(|c: char| c == '.' || c.is_whitespace())
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+    if name.is_empty() {
+        return None;
+    }
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.ends_with(|c: char| c.is_ascii_digit()));
+    if reserved {
+        name.insert(0, '_');
+    }
+    if name.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    Some(name)
+}
+
+/// Temporary file next to target, download is written there and then renamed
+pub fn part_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{name}.{}.part", uuid::Uuid::new_v4()))
+}
+
+/// Moves finished temporary file to target
+///
+/// Unless `force` is set, existing target is never overwritten, even if it appears meanwhile
+/// (error kind is then `AlreadyExists`).
+pub async fn persist(temp: &Path, target: &Path, force: bool) -> std::io::Result<()> {
+    if force {
+        return fs::rename(temp, target).await;
+    }
+    match fs::hard_link(temp, target).await {
+        Ok(()) => fs::remove_file(temp).await,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(e),
+        // filesystem without hard links
+        Err(_) => {
+            if fs::try_exists(target).await? {
+                return Err(std::io::ErrorKind::AlreadyExists.into());
+            }
+            fs::rename(temp, target).await
+        }
+    }
+}
+
+/// Creates empty placeholder for target, fails with `AlreadyExists` if target exists
+///
+/// Used when data must be written to target directly, placeholder is then replaced.
+pub async fn claim(target: &Path) -> std::io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)
+        .await
+        .map(|_| ())
+}
+
+/// Returns path in `dir` for file `name`, which does not exist yet
+///
+/// If file exists, number is appended to its stem (`name (1).ext`).
+pub async fn unique_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let path = dir.join(name);
+    if !fs::try_exists(&path).await? {
+        return Ok(path);
+    }
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = name
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    for i in 1.. {
+        let path = dir.join(format!("{stem} ({i}){extension}"));
+        if !fs::try_exists(&path).await? {
+            return Ok(path);
+        }
+    }
+    unreachable!("Some numbered file name is free")
+}
+
+/// Writes file via temporary file, so existing content is never left half written
+///
+/// New file is readable only by owner (on unix), as it can hold keys or private state.
+pub async fn write_atomic(file: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // permissions are set only when file is created, so leftover is not reused
+    fs::remove_file(&tmp).await.ok();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let res = async {
+        let mut out = options.open(&tmp).await?;
+        out.write_all(data).await?;
+        out.sync_all().await?;
+        fs::rename(&tmp, file).await
+    }
+    .await;
+    if res.is_err() {
+        fs::remove_file(&tmp).await.ok();
+    }
+    Ok(res?)
+}
+
+/// Computes BLAKE3 hash of file, same as hash of blob with its content, and its size
+pub async fn hash_file(file: &Path) -> Result<(Hash, u64)> {
+    let file = file.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(&file)?)?;
+        Ok((Hash::from(hasher.finalize()), hasher.count()))
+    })
+    .await?
+}
+
+/// Sets modification time of downloaded file as it was on sender side
+///
+/// Permissions from peer are not copied (they could make file executable), file keeps
+/// permissions given by local umask and is only made read-only if it was read-only on sender side.
+pub async fn apply_attributes(file: &Path, info: &FileInfo) -> Result<()> {
+    let file = file.to_path_buf();
+    let (modified, read_only) = (
+        info.modified,
+        info.mode.is_some_and(|mode| mode & 0o222 == 0),
+    );
+    tokio::task::spawn_blocking(move || {
+        if let Some(modified) = modified {
+            let file = std::fs::OpenOptions::new().write(true).open(&file)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        }
+        if read_only {
+            let mut permissions = std::fs::metadata(&file)?.permissions();
+            permissions.set_readonly(true);
+            std::fs::set_permissions(&file, permissions)?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn dots_are_not_kept_at_start() {
+        assert_eq!(sanitize_file_name(".."), None);
+        assert_eq!(sanitize_file_name("."), None);
+        assert_eq!(sanitize_file_name("...hidden"), Some("hidden".to_string()));
+        assert_eq!(sanitize_file_name(" name. "), Some("name".to_string()));
+    }
+
+    #[test]
+    fn separators_are_replaced() {
+        assert_eq!(
+            sanitize_file_name("../../etc/passwd"),
+            Some("_.._etc_passwd".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("..\\..\\boot.ini"),
+            Some("_.._boot.ini".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("/abs/path"),
+            Some("_abs_path".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("a:b*c?.txt"),
+            Some("a_b_c_.txt".to_string())
+        );
+        assert_eq!(
+            sanitize_file_name("new\nline"),
+            Some("new_line".to_string())
+        );
+        assert_eq!(sanitize_file_name("/"), Some("_".to_string()));
+        assert_eq!(sanitize_file_name(""), None);
+    }
+
+    #[test]
+    fn reserved_names_are_escaped() {
+        assert_eq!(sanitize_file_name("CON"), Some("_CON".to_string()));
+        assert_eq!(sanitize_file_name("con.txt"), Some("_con.txt".to_string()));
+        assert_eq!(
+            sanitize_file_name("nul.tar.gz"),
+            Some("_nul.tar.gz".to_string())
+        );
+        assert_eq!(sanitize_file_name("COM1"), Some("_COM1".to_string()));
+        assert_eq!(
+            sanitize_file_name("lpt9.log"),
+            Some("_lpt9.log".to_string())
+        );
+        assert_eq!(sanitize_file_name("COM10"), Some("COM10".to_string()));
+        assert_eq!(sanitize_file_name("console"), Some("console".to_string()));
+        assert_eq!(
+            sanitize_file_name("report.con"),
+            Some("report.con".to_string())
+        );
+    }
+
+    #[test]
+    fn long_names_are_truncated_at_char_boundary() {
+        let name = "é".repeat(MAX_NAME_LEN);
+        let sanitized = sanitize_file_name(&name).unwrap();
+        assert!(sanitized.len() <= MAX_NAME_LEN);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[tokio::test]
+    async fn existing_target_gets_numbered_name() {
+        let dir = TestDir::new();
+        assert_eq!(
+            unique_path(dir.root(), "a.txt").await.unwrap(),
+            dir.path("a.txt")
+        );
+        std::fs::write(dir.path("a.txt"), b"first").unwrap();
+        assert_eq!(
+            unique_path(dir.root(), "a.txt").await.unwrap(),
+            dir.path("a (1).txt")
+        );
+        std::fs::write(dir.path("a (1).txt"), b"second").unwrap();
+        assert_eq!(
+            unique_path(dir.root(), "a.txt").await.unwrap(),
+            dir.path("a (2).txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_target_is_replaced_only_with_force() {
+        let dir = TestDir::new();
+        let target = dir.path("a.txt");
+        std::fs::write(&target, b"old").unwrap();
+        let temp = part_path(&target);
+        std::fs::write(&temp, b"new").unwrap();
+
+        let err = persist(&temp, &target, false).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+
+        persist(&temp, &target, true).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert!(!temp.exists());
+    }
+
+    #[tokio::test]
+    async fn same_name_is_claimed_once() {
+        let dir = TestDir::new();
+        let target = dir.path("a.txt");
+        claim(&target).await.unwrap();
+        let err = claim(&target).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            unique_path(dir.root(), "a.txt").await.unwrap(),
+            dir.path("a (1).txt")
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! Identity keys stored in data directory as `<name>.id`
+//!
+//! Key file is either legacy raw 32 bytes secret key or versioned format:
+//! magic, version byte, encryption flag and the key (sealed by passphrase if encrypted).
+
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result};
+use clap::Subcommand;
+use iroh::{PublicKey, SecretKey};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{channel::KeyHandover, crypto, files};
+
+const KEY_MAGIC: &[u8; 4] = b"SFID";
+const KEY_FORMAT_VERSION: u8 = 1;
+const FLAG_ENCRYPTED: u8 = 1;
+/// Passphrase can be provided in environment variable for non-interactive use
+pub const PASSPHRASE_ENV: &str = "SEND_FILE_PASSPHRASE";
+
+/// Checks that identity name can be used as file name in data directory
+///
+/// Names are not sanitized, but refused, so they never point outside data directory.
+pub fn check_name(name: &str) -> Result<()> {
+    if files::sanitize_file_name(name).as_deref() != Some(name) {
+        anyhow::bail!("Invalid identity name {name}, it must be plain file name");
+    }
+    Ok(())
+}
+
+// extension is appended, names can contain dots, so `with_extension` would replace their suffix
+pub fn identity_file(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(format!("{name}.id"))
+}
+
+/// Configuration file of identity, overrides data directory configuration
+pub fn config_file(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(format!("{name}.toml"))
+}
+
+/// Chain of key handovers of identity, created by key rotation
+pub fn handover_file(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(format!("{name}.handover"))
+}
+
+pub async fn load_handovers(data_dir: &Path, name: &str) -> Result<Vec<KeyHandover>> {
+    let file = handover_file(data_dir, name);
+    if fs::try_exists(&file).await? {
+        let data = fs::read(&file).await?;
+        Ok(postcard::from_bytes(&data).context("Invalid handover file")?)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Drops handovers following the one to `current` key
+///
+/// Rotation writes chain before new key, so interrupted rotation leaves chain ending with key
+/// which was never saved.
+pub fn trim_handovers(chain: &mut Vec<KeyHandover>, current: &PublicKey) {
+    let len = chain
+        .iter()
+        .rposition(|handover| handover.new == *current)
+        .map_or(0, |pos| pos + 1);
+    chain.truncate(len);
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.len() > KEY_MAGIC.len() + 2
+        && data.starts_with(KEY_MAGIC)
+        && data[KEY_MAGIC.len() + 1] & FLAG_ENCRYPTED != 0
+}
+
+/// Encodes key in versioned format, encrypted if passphrase is given
+pub fn encode_key(key: &SecretKey, passphrase: Option<&str>) -> Result<Vec<u8>> {
+    let mut data = KEY_MAGIC.to_vec();
+    data.push(KEY_FORMAT_VERSION);
+    match passphrase {
+        Some(passphrase) => {
+            data.push(FLAG_ENCRYPTED);
+            data.extend(crypto::seal(passphrase, &key.to_bytes())?);
+        }
+        None => {
+            data.push(0);
+            data.extend(key.to_bytes());
+        }
+    }
+    Ok(data)
+}
+
+/// Decodes key file content, `passphrase` is called only if key is encrypted
+pub fn decode_key(data: &[u8], passphrase: impl FnOnce() -> Result<String>) -> Result<SecretKey> {
+    let key_data = if data.len() == 32 {
+        data.to_vec()
+    } else if let Some(rest) = data.strip_prefix(KEY_MAGIC) {
+        let (&version, rest) = rest.split_first().context("Truncated key file")?;
+        if version != KEY_FORMAT_VERSION {
+            anyhow::bail!("Unsupported key file version {version}");
+        }
+        let (&flags, rest) = rest.split_first().context("Truncated key file")?;
+        if flags & FLAG_ENCRYPTED != 0 {
+            crypto::open(&passphrase()?, rest).context("Cannot decrypt identity key")?
+        } else {
+            rest.to_vec()
+        }
+    } else {
+        anyhow::bail!("Invalid key file");
+    };
+    let key_ref: &[u8; 32] = key_data[..]
+        .try_into()
+        .context("Secret key must be 32 bytes")?;
+    Ok(SecretKey::from_bytes(key_ref))
+}
+
+pub fn prompt_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+pub fn new_passphrase(name: &str) -> Result<String> {
+    let passphrase = prompt_passphrase(&format!("New passphrase for identity {name}: "))?;
+    if passphrase.is_empty() {
+        anyhow::bail!("Empty passphrase");
+    }
+    if std::env::var(PASSPHRASE_ENV).is_err()
+        && rpassword::prompt_password("Repeat passphrase: ")? != passphrase
+    {
+        anyhow::bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+/// Writes key file readable only by owner, existing key is never left half written
+pub async fn save_key(file: &Path, data: &[u8]) -> Result<()> {
+    files::write_atomic(file, data).await
+}
+
+/// Loads identity key, creates new one if it does not exist
+///
+/// With `encrypt` new keys are created encrypted and existing unencrypted keys are migrated.
+pub async fn load_identity(data_dir: &Path, name: &str, encrypt: bool) -> Result<SecretKey> {
+    let file = identity_file(data_dir, name);
+    if fs::try_exists(&file).await? {
+        let key_data = fs::read(&file).await?;
+        let key = decode_key(&key_data, || {
+            prompt_passphrase(&format!("Passphrase for identity {name}: "))
+        })?;
+        if !is_encrypted(&key_data) {
+            if encrypt {
+                let passphrase = new_passphrase(name)?;
+                save_key(&file, &encode_key(&key, Some(&passphrase))?).await?;
+                println!("Identity key {name} is now encrypted");
+            } else {
+                warn!("Identity key {name} is stored unencrypted, use --encrypt-identity");
+            }
+        }
+        Ok(key)
+    } else {
+        let key = SecretKey::generate(&mut rand::rng());
+        let passphrase = if encrypt {
+            Some(new_passphrase(name)?)
+        } else {
+            None
+        };
+        save_key(&file, &encode_key(&key, passphrase.as_deref())?).await?;
+        Ok(key)
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum IdentityCommand {
+    /// List identities in data directory
+    List,
+    /// Show public key (endpoint id) of identity
+    Show { name: String },
+    /// Export identity key to file (as is) or as mnemonic phrase
+    Export {
+        name: String,
+        #[arg(
+            long,
+            short,
+            help = "Export key file here, otherwise mnemonic is printed"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// Import identity key from file or mnemonic phrase (prompted if file is not given)
+    Import {
+        name: String,
+        #[arg(long, short, help = "Key file to import")]
+        file: Option<PathBuf>,
+        #[arg(long, help = "Encrypt key imported from mnemonic with passphrase", action = clap::ArgAction::SetTrue)]
+        encrypt: bool,
+    },
+    /// Replace identity key with new one, old key signs handover to the new key
+    Rotate { name: String },
+    /// Delete identity key
+    Delete {
+        name: String,
+        #[arg(long, short, help = "Do not ask for confirmation", action = clap::ArgAction::SetTrue)]
+        yes: bool,
+        #[arg(long, help = "Remove also store and config file of identity", action = clap::ArgAction::SetTrue)]
+        purge: bool,
+    },
+}
+
+async fn read_key_file(data_dir: &Path, name: &str) -> Result<Vec<u8>> {
+    fs::read(identity_file(data_dir, name))
+        .await
+        .with_context(|| format!("Identity {name} not found"))
+}
+
+async fn read_key(data_dir: &Path, name: &str) -> Result<SecretKey> {
+    let data = read_key_file(data_dir, name).await?;
+    decode_key(&data, || {
+        prompt_passphrase(&format!("Passphrase for identity {name}: "))
+    })
+}
+
+fn read_line(prompt: &str) -> Result<String> {
+    print!("{prompt}");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+pub async fn run_command(data_dir: &Path, cmd: &IdentityCommand) -> Result<()> {
+    match cmd {
+        IdentityCommand::List => {}
+        IdentityCommand::Show { name }
+        | IdentityCommand::Export { name, .. }
+        | IdentityCommand::Import { name, .. }
+        | IdentityCommand::Rotate { name }
+        | IdentityCommand::Delete { name, .. } => check_name(name)?,
+    }
+    match cmd {
+        IdentityCommand::List => {
+            let mut entries = fs::read_dir(data_dir).await?;
+            let mut identities = vec![];
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "id")
+                    && let Some(name) = path.file_stem().and_then(|n| n.to_str())
+                {
+                    let data = fs::read(&path).await?;
+                    let description = if is_encrypted(&data) {
+                        "(encrypted)".to_string()
+                    } else {
+                        decode_key(&data, || anyhow::bail!("Key is encrypted"))
+                            .map(|key| key.public().to_string())
+                            .unwrap_or_else(|e| format!("(invalid: {e})"))
+                    };
+                    identities.push((name.to_string(), description));
+                }
+            }
+            identities.sort();
+            for (name, description) in identities {
+                println!("{name}\t{description}");
+            }
+        }
+        IdentityCommand::Show { name } => {
+            let key = read_key(data_dir, name).await?;
+            println!("{}", key.public());
+        }
+        IdentityCommand::Export { name, file } => {
+            match file {
+                Some(file) => {
+                    // exported as is, so passphrase is not needed
+                    let data = read_key_file(data_dir, name).await?;
+                    if fs::try_exists(file).await? {
+                        anyhow::bail!("File {} already exists", file.display());
+                    }
+                    files::write_atomic(file, &data).await?;
+                    println!("Identity {name} exported to {}", file.display());
+                }
+                None => {
+                    let key = read_key(data_dir, name).await?;
+                    let mnemonic = bip39::Mnemonic::from_entropy(&key.to_bytes())?;
+                    println!("{mnemonic}");
+                }
+            }
+        }
+        IdentityCommand::Import {
+            name,
+            file: source,
+            encrypt,
+        } => {
+            let file = identity_file(data_dir, name);
+            if fs::try_exists(&file).await? {
+                anyhow::bail!("Identity {name} already exists");
+            }
+            let data = match source {
+                Some(source) => {
+                    let data = fs::read(source).await?;
+                    // validate key file before import
+                    decode_key(&data, || {
+                        prompt_passphrase(&format!("Passphrase for {}: ", source.display()))
+                    })?;
+                    data
+                }
+                None => {
+                    // mnemonic is the secret key, so it is not echoed
+                    let words = rpassword::prompt_password("Mnemonic phrase: ")?;
+                    let entropy = bip39::Mnemonic::parse(words.trim())?.to_entropy();
+                    let key_ref: &[u8; 32] = entropy[..]
+                        .try_into()
+                        .context("Mnemonic must have 24 words")?;
+                    let key = SecretKey::from_bytes(key_ref);
+                    let passphrase = if *encrypt {
+                        Some(new_passphrase(name)?)
+                    } else {
+                        None
+                    };
+                    encode_key(&key, passphrase.as_deref())?
+                }
+            };
+            fs::create_dir_all(data_dir).await?;
+            save_key(&file, &data).await?;
+            println!("Identity {name} imported");
+        }
+        IdentityCommand::Rotate { name } => {
+            let file = identity_file(data_dir, name);
+            let data = fs::read(&file)
+                .await
+                .with_context(|| format!("Identity {name} not found"))?;
+            let passphrase = if is_encrypted(&data) {
+                Some(prompt_passphrase(&format!(
+                    "Passphrase for identity {name}: "
+                ))?)
+            } else {
+                None
+            };
+            let old_key = decode_key(&data, || passphrase.clone().context("Missing passphrase"))?;
+            let new_key = SecretKey::generate(&mut rand::rng());
+
+            let mut chain = load_handovers(data_dir, name).await?;
+            trim_handovers(&mut chain, &old_key.public());
+            chain.push(KeyHandover::new(&old_key, new_key.public())?);
+            // chain is written first, so crash never leaves new key without its handover,
+            // crash before key is saved leaves extra handover, which is trimmed
+            files::write_atomic(
+                &handover_file(data_dir, name),
+                &postcard::to_stdvec(&chain)?,
+            )
+            .await?;
+            save_key(&file, &encode_key(&new_key, passphrase.as_deref())?).await?;
+            println!(
+                "Identity {name} rotated from {} to {}",
+                old_key.public(),
+                new_key.public()
+            );
+        }
+        IdentityCommand::Delete { name, yes, purge } => {
+            let file = identity_file(data_dir, name);
+            if !fs::try_exists(&file).await? {
+                anyhow::bail!("Identity {name} not found");
+            }
+            if !yes {
+                println!("Deleting identity {name} cannot be undone, unless you have exported it.");
+                let answer = read_line("Type identity name to confirm: ")?;
+                if answer != *name {
+                    anyhow::bail!("Not confirmed, identity kept");
+                }
+            }
+            fs::remove_file(&file).await?;
+            let handovers = handover_file(data_dir, name);
+            if fs::try_exists(&handovers).await? {
+                fs::remove_file(&handovers).await?;
+            }
+            println!("Identity {name} deleted");
+            let store = crate::context::store_path(data_dir, name);
+            let config = config_file(data_dir, name);
+            if fs::try_exists(&store).await? {
+                if *purge {
+                    fs::remove_dir_all(&store).await?;
+                    println!("Store {} removed", store.display());
+                } else {
+                    println!(
+                        "Store {} was kept, use --purge to remove it",
+                        store.display()
+                    );
+                }
+            }
+            if fs::try_exists(&config).await? {
+                if *purge {
+                    fs::remove_file(&config).await?;
+                    println!("Config {} removed", config.display());
+                } else {
+                    println!(
+                        "Config {} was kept, use --purge to remove it",
+                        config.display()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    fn no_passphrase() -> Result<String> {
+        panic!("Passphrase must not be asked for unencrypted key")
+    }
+
+    #[test]
+    fn legacy_key_is_decoded() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let decoded = decode_key(&key.to_bytes(), no_passphrase).unwrap();
+        assert_eq!(decoded.to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn plain_versioned_key_is_decoded() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let data = encode_key(&key, None).unwrap();
+        assert!(data.starts_with(KEY_MAGIC));
+        assert!(!is_encrypted(&data));
+        let decoded = decode_key(&data, no_passphrase).unwrap();
+        assert_eq!(decoded.to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn encrypted_key_is_decoded() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let data = encode_key(&key, Some("secret")).unwrap();
+        assert!(is_encrypted(&data));
+        assert!(!data.windows(32).any(|window| window == key.to_bytes()));
+        let decoded = decode_key(&data, || Ok("secret".to_string())).unwrap();
+        assert_eq!(decoded.to_bytes(), key.to_bytes());
+        assert!(decode_key(&data, || Ok("wrong".to_string())).is_err());
+        assert!(decode_key(&data, || anyhow::bail!("Key is encrypted")).is_err());
+    }
+
+    #[test]
+    fn invalid_key_files_are_rejected() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let mut data = encode_key(&key, None).unwrap();
+        data[KEY_MAGIC.len()] = KEY_FORMAT_VERSION + 1;
+        assert!(decode_key(&data, no_passphrase).is_err());
+        assert!(decode_key(KEY_MAGIC, no_passphrase).is_err());
+        assert!(decode_key(&[0u8; 31], no_passphrase).is_err());
+        let mut data = encode_key(&key, None).unwrap();
+        data.pop();
+        assert!(decode_key(&data, no_passphrase).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = TestDir::new();
+        let file = identity_file(dir.root(), "alice");
+        // leftover temporary file with loose permissions must not be reused
+        std::fs::write(dir.path("alice.id.tmp"), b"stale").unwrap();
+        std::fs::set_permissions(
+            dir.path("alice.id.tmp"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+        let key = SecretKey::generate(&mut rand::rng());
+        save_key(&file, &encode_key(&key, None).unwrap())
+            .await
+            .unwrap();
+        let mode = fs::metadata(&file).await.unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let decoded = decode_key(&fs::read(&file).await.unwrap(), no_passphrase).unwrap();
+        assert_eq!(decoded.to_bytes(), key.to_bytes());
+    }
+
+    #[test]
+    fn names_outside_data_dir_are_refused() {
+        check_name("alice").unwrap();
+        check_name("alice.work").unwrap();
+        for name in [
+            "", ".", "..", "../x", "../../x", "a/b", "a\\b", "/abs", ".hidden",
+        ] {
+            assert!(check_name(name).is_err(), "{name} must be refused");
+        }
+    }
+
+    #[tokio::test]
+    async fn dotted_names_are_separate_identities() {
+        let dir = TestDir::new();
+        let (alice, work) = ("alice", "alice.work");
+        assert_ne!(
+            identity_file(dir.root(), alice),
+            identity_file(dir.root(), work)
+        );
+        assert_ne!(
+            handover_file(dir.root(), alice),
+            handover_file(dir.root(), work)
+        );
+        assert_ne!(
+            config_file(dir.root(), alice),
+            config_file(dir.root(), work)
+        );
+        let key = SecretKey::generate(&mut rand::rng());
+        save_key(
+            &identity_file(dir.root(), alice),
+            &encode_key(&key, None).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(!identity_file(dir.root(), work).exists());
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let sealed = crypto::seal("secret", b"data").unwrap();
+        assert_eq!(crypto::open("secret", &sealed).unwrap(), b"data");
+        assert!(crypto::open("wrong", &sealed).is_err());
+    }
+
+    #[test]
+    fn handovers_after_current_key_are_trimmed() {
+        let keys: Vec<SecretKey> = (0..3)
+            .map(|_| SecretKey::generate(&mut rand::rng()))
+            .collect();
+        let chain: Vec<KeyHandover> = keys
+            .windows(2)
+            .map(|pair| KeyHandover::new(&pair[0], pair[1].public()).unwrap())
+            .collect();
+
+        let mut trimmed = chain.clone();
+        trim_handovers(&mut trimmed, &keys[2].public());
+        assert_eq!(trimmed.len(), 2);
+        // rotation to keys[2] was interrupted before key was saved
+        let mut trimmed = chain.clone();
+        trim_handovers(&mut trimmed, &keys[1].public());
+        assert_eq!(trimmed.len(), 1);
+        let mut trimmed = chain;
+        trim_handovers(&mut trimmed, &keys[0].public());
+        assert!(trimmed.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! Bandwidth limits for uploads and downloads
+//!
+//! Every direction has global limit and limit applied to each peer separately, both can be
+//! changed at runtime. Limits are token buckets with one second burst, transfers wait for
+//! tokens after each chunk.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh::EndpointId;
+
+use crate::store::format_size;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Runtime change of limit requested by `#limit` command
+#[derive(Debug, Clone, Copy)]
+pub struct LimitChange {
+    pub direction: Direction,
+    pub per_peer: bool,
+    /// Rate in KiB/s, 0 means unlimited
+    pub rate: u64,
+}
+
+/// Token bucket, rate in bytes per second, 0 means unlimited
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes tokens for `bytes`, returns how long to wait until they are available
+    fn reserve(&mut self, bytes: u64) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+
+    fn set_rate(&mut self, rate: u64) {
+        *self = Bucket::new(rate);
+    }
+}
+
+/// Per peer bucket unused this long is dropped, it is full anyway after one second
+const PEER_BUCKET_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct DirectionLimits {
+    global: Bucket,
+    per_peer: u64,
+    peers: HashMap<EndpointId, Bucket>,
+}
+
+impl DirectionLimits {
+    fn new(global: u64, per_peer: u64) -> Self {
+        DirectionLimits {
+            global: Bucket::new(global),
+            per_peer,
+            peers: HashMap::new(),
+        }
+    }
+
+    fn reserve(&mut self, peer: Option<EndpointId>, bytes: u64) -> Duration {
+        let global = self.global.reserve(bytes);
+        let per_peer = self.per_peer;
+        let peer = match peer {
+            Some(peer) if per_peer > 0 => {
+                if !self.peers.contains_key(&peer) {
+                    self.prune_idle();
+                }
+                self.peers
+                    .entry(peer)
+                    .or_insert_with(|| Bucket::new(per_peer))
+                    .reserve(bytes)
+            }
+            _ => Duration::ZERO,
+        };
+        global.max(peer)
+    }
+
+    fn prune_idle(&mut self) {
+        let now = Instant::now();
+        self.peers
+            .retain(|_, bucket| now.duration_since(bucket.last) < PEER_BUCKET_IDLE);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    inner: Arc<Mutex<[DirectionLimits; 2]>>,
+}
+
+fn index(direction: Direction) -> usize {
+    match direction {
+        Direction::Upload => 0,
+        Direction::Download => 1,
+    }
+}
+
+impl Limits {
+    /// Creates limits from rates in bytes per second
+    pub fn new(upload: u64, upload_per_peer: u64, download: u64, download_per_peer: u64) -> Self {
+        Limits {
+            inner: Arc::new(Mutex::new([
+                DirectionLimits::new(upload, upload_per_peer),
+                DirectionLimits::new(download, download_per_peer),
+            ])),
+        }
+    }
+
+    /// Waits until `bytes` can be transferred within limits
+    pub async fn throttle(&self, direction: Direction, peer: Option<EndpointId>, bytes: u64) {
+        let delay = self.inner.lock().unwrap()[index(direction)].reserve(peer, bytes);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub fn is_limited(&self, direction: Direction) -> bool {
+        let limits = &self.inner.lock().unwrap()[index(direction)];
+        limits.global.rate > 0 || limits.per_peer > 0
+    }
+
+    pub fn apply(&self, change: LimitChange) {
+        let rate = change.rate.saturating_mul(1024);
+        if change.per_peer {
+            self.set_per_peer(change.direction, rate);
+        } else {
+            self.set_global(change.direction, rate);
+        }
+    }
+
+    pub fn set_global(&self, direction: Direction, rate: u64) {
+        self.inner.lock().unwrap()[index(direction)]
+            .global
+            .set_rate(rate);
+    }
+
+    pub fn set_per_peer(&self, direction: Direction, rate: u64) {
+        let limits = &mut self.inner.lock().unwrap()[index(direction)];
+        limits.per_peer = rate;
+        for bucket in limits.peers.values_mut() {
+            bucket.set_rate(rate);
+        }
+    }
+
+    /// Human readable description of current limits
+    pub fn describe(&self) -> Vec<String> {
+        let limits = self.inner.lock().unwrap();
+        let rate = |rate: u64| match rate {
+            0 => "unlimited".to_string(),
+            rate => format!("{}/s", format_size(rate)),
+        };
+        [
+            ("Upload", Direction::Upload),
+            ("Download", Direction::Download),
+        ]
+        .into_iter()
+        .map(|(name, direction)| {
+            let limits = &limits[index(direction)];
+            format!(
+                "{name}: {} total, {} per peer",
+                rate(limits.global.rate),
+                rate(limits.per_peer)
+            )
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1000;
+
+    fn assert_close(duration: Duration, secs: f64) {
+        assert!(
+            (duration.as_secs_f64() - secs).abs() < 0.05,
+            "{duration:?} is not {secs} s"
+        );
+    }
+
+    #[test]
+    fn burst_is_free_then_waits_for_rate() {
+        let mut bucket = Bucket::new(RATE);
+        assert_eq!(bucket.reserve(RATE), Duration::ZERO);
+        assert_close(bucket.reserve(RATE / 2), 0.5);
+        // reservations queue up
+        assert_close(bucket.reserve(RATE), 1.5);
+    }
+
+    #[test]
+    fn tokens_refill_up_to_burst() {
+        let mut bucket = Bucket::new(RATE);
+        bucket.reserve(RATE);
+        bucket.last -= Duration::from_millis(500);
+        assert_eq!(bucket.reserve(RATE / 2), Duration::ZERO);
+        // long idle time does not give more than one second of tokens
+        bucket.last -= Duration::from_secs(10);
+        assert_eq!(bucket.reserve(RATE), Duration::ZERO);
+        assert_close(bucket.reserve(RATE), 1.0);
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let mut bucket = Bucket::new(0);
+        assert_eq!(bucket.reserve(u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn slower_of_global_and_peer_limit_applies() {
+        let peer = iroh::SecretKey::generate(&mut rand::rng()).public();
+        let mut limits = DirectionLimits::new(RATE * 10, RATE);
+        assert_eq!(limits.reserve(Some(peer), RATE), Duration::ZERO);
+        assert_close(limits.reserve(Some(peer), RATE), 1.0);
+        // peer limit does not apply to unknown peer
+        assert_eq!(limits.reserve(None, RATE), Duration::ZERO);
+    }
+
+    #[test]
+    fn idle_peer_buckets_are_pruned() {
+        let peer = || iroh::SecretKey::generate(&mut rand::rng()).public();
+        let (idle, active, new) = (peer(), peer(), peer());
+        let mut limits = DirectionLimits::new(0, RATE);
+        limits.reserve(Some(idle), RATE);
+        limits.reserve(Some(active), RATE);
+        limits.peers.get_mut(&idle).unwrap().last -= PEER_BUCKET_IDLE;
+        limits.reserve(Some(new), RATE);
+        assert!(!limits.peers.contains_key(&idle));
+        assert!(limits.peers.contains_key(&active));
+        assert!(limits.peers.contains_key(&new));
+    }
+}
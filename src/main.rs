@@ -1,49 +1,223 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    io::Write as _,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{Context as _, Result, anyhow};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory as _, Parser, Subcommand};
 use futures_lite::StreamExt as _;
-use iroh::{EndpointAddr, protocol::Router};
-use iroh_blobs::{BlobsProtocol, ticket::BlobTicket};
+use iroh::{
+    Endpoint, EndpointAddr, EndpointId, RelayUrl, Watcher as _, endpoint::ConnectionType,
+    protocol::Router,
+};
+use iroh_blobs::{
+    BlobFormat, BlobsProtocol, Hash,
+    api::{
+        blobs::{AddPathOptions, BlobStatus, ImportMode},
+        remote::GetProgressItem,
+    },
+    provider::events::{
+        ConnectMode, EventMask, EventSender, ProviderMessage, RequestMode, ThrottleMode,
+    },
+    ticket::BlobTicket,
+};
 use iroh_gossip::{
     Gossip, TopicId,
-    api::{Event, GossipReceiver},
+    api::{Event, GossipReceiver, GossipSender},
 };
-use tokio::fs;
+use tokio::{fs, io::AsyncReadExt as _};
 use tracing::error;
 
 use crate::{
-    channel::{Message, MessageBody, MessageEnvelope, Ticket},
-    command::Command,
+    channel::{FileInfo, FileTicket, KeyHandover, Message, MessageBody, MessageEnvelope, Ticket},
+    command::{Command, Password},
+    config::{AutoDownload, ExportMode, PeerAddr},
     context::Context,
+    limits::{Direction, Limits},
+    queue::{DownloadJob, Priority},
+    store::AccessLog,
+    watch::{DEFAULT_WATCH_DEBOUNCE, FolderWatch},
 };
 
 mod channel;
 mod command;
+mod config;
 mod context;
+mod crypto;
+mod diagnostics;
+mod files;
+mod identity;
+mod limits;
+mod qr;
+mod queue;
+mod rendezvous;
+mod store;
+#[cfg(test)]
+mod testing;
+mod watch;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct Args {
     #[arg(long, short, help = "Data directory", default_value = "./test-data")]
     data_dir: PathBuf,
-    #[arg(long, short, alias = "name", help = "User name", required = true)]
-    identity: String,
+    #[arg(
+        long,
+        short,
+        alias = "name",
+        help = "User name (required for start and join)"
+    )]
+    identity: Option<String>,
+    #[arg(long, help = "Name shown to other users (identity name by default)")]
+    display_name: Option<String>,
     #[arg(long, short, help = "Enforce creation of new topic", action = clap::ArgAction::SetTrue)]
     new_topic: bool,
-    #[arg(long, help = "Disable mdns (local LAN) discovery", action = clap::ArgAction::SetTrue)]
+    #[arg(long, help = "Disable mdns (local LAN) discovery", action = clap::ArgAction::SetTrue, overrides_with = "enable_mdns")]
     disable_mdns: bool,
-    #[arg(long, help = "Enable DHT discovery (your record will be published in BitTorrent Mainline DHT)", action = clap::ArgAction::SetTrue)]
+    #[arg(long, help = "Enable mdns discovery even if disabled in config", action = clap::ArgAction::SetTrue, overrides_with = "disable_mdns")]
+    enable_mdns: bool,
+    #[arg(long, help = "Enable DHT discovery (your record will be published in BitTorrent Mainline DHT)", action = clap::ArgAction::SetTrue, overrides_with = "disable_dht")]
     enable_dht: bool,
-    #[arg(long, help = "Disable public (number-0) relays", action = clap::ArgAction::SetTrue)]
+    #[arg(long, help = "Disable DHT discovery even if enabled in config", action = clap::ArgAction::SetTrue, overrides_with = "enable_dht")]
+    disable_dht: bool,
+    #[arg(long, help = "Disable public (number-0) relays", action = clap::ArgAction::SetTrue, overrides_with = "enable_relays")]
     disable_relays: bool,
+    #[arg(long, help = "Use relays even if disabled in config", action = clap::ArgAction::SetTrue, overrides_with = "disable_relays")]
+    enable_relays: bool,
+    #[arg(
+        long = "relay",
+        value_name = "URL",
+        help = "Custom relay server URL used instead of public relays (can be repeated)"
+    )]
+    relays: Vec<RelayUrl>,
+    #[arg(
+        long = "bind",
+        value_name = "ADDR:PORT",
+        help = "Local address to bind, one IPv4 and one IPv6 address can be given [default: random port]"
+    )]
+    bind: Vec<SocketAddr>,
+    #[arg(
+        long = "peer",
+        value_name = "ID@ADDR:PORT",
+        help = "Static address of peer, for networks without discovery (can be repeated)"
+    )]
+    peers: Vec<PeerAddr>,
+    #[arg(
+        long,
+        help = "File with static peer addresses, one <ID>@<ADDR:PORT> per line"
+    )]
+    peers_file: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Give up joining channel after this many seconds [default: 120]"
+    )]
+    join_timeout: Option<u64>,
+    #[arg(
+        long,
+        help = "Encrypt identity key with passphrase (existing unencrypted key is migrated)",
+        action = clap::ArgAction::SetTrue
+    )]
+    encrypt_identity: bool,
+    #[arg(long, help = "Room name included in invite tickets")]
+    room: Option<String>,
+    #[arg(
+        long,
+        help = "Validity of generated invite tickets in minutes (valid forever if not set)"
+    )]
+    invite_ttl: Option<u64>,
+    #[arg(long, help = "Show generated tickets also as QR codes", action = clap::ArgAction::SetTrue)]
+    qr: bool,
+    #[arg(long, help = "Save QR codes also as PNG files in data directory (implies --qr)", action = clap::ArgAction::SetTrue)]
+    qr_png: bool,
+    #[arg(
+        long,
+        help = "Directory for downloaded files [default: <DATA_DIR>/downloads]"
+    )]
+    downloads_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Store garbage collection interval in seconds [default: 60]"
+    )]
+    gc_interval: Option<u64>,
+    #[arg(
+        long,
+        help = "Store size limit in MiB, least recently used downloads are evicted above it"
+    )]
+    store_quota: Option<u64>,
+    #[arg(long, help = "Share files by reference instead of copying them into store (like #share --ref)", action = clap::ArgAction::SetTrue, overrides_with = "no_reference_imports")]
+    reference_imports: bool,
+    #[arg(long, help = "Copy shared files into store even if reference imports are set in config", action = clap::ArgAction::SetTrue, overrides_with = "reference_imports")]
+    no_reference_imports: bool,
+    #[arg(
+        long,
+        value_enum,
+        help = "Automatically download shared files [default: never]"
+    )]
+    auto_download: Option<AutoDownload>,
+    #[arg(
+        long,
+        value_enum,
+        help = "How downloaded files are exported from store [default: copy]"
+    )]
+    export_mode: Option<ExportMode>,
+    #[arg(long, help = "Restore modification time and read-only flag of downloaded files", action = clap::ArgAction::SetTrue, overrides_with = "no_preserve_attributes")]
+    preserve_attributes: bool,
+    #[arg(long, help = "Do not restore file attributes even if set in config", action = clap::ArgAction::SetTrue, overrides_with = "preserve_attributes")]
+    no_preserve_attributes: bool,
+    #[arg(long, help = "Total upload limit in KiB/s")]
+    upload_limit: Option<u64>,
+    #[arg(long, help = "Upload limit for each peer in KiB/s")]
+    upload_limit_per_peer: Option<u64>,
+    #[arg(long, help = "Total download limit in KiB/s")]
+    download_limit: Option<u64>,
+    #[arg(long, help = "Download limit for each peer in KiB/s")]
+    download_limit_per_peer: Option<u64>,
+    #[arg(long, help = "Number of downloads running at once [default: 3]")]
+    parallel_downloads: Option<usize>,
+    #[arg(
+        long,
+        help = "Share files placed in this directory automatically (created if missing)"
+    )]
+    watch_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Seconds watched file must stay unchanged before it is shared [default: 3]"
+    )]
+    watch_debounce: Option<u64>,
+    #[arg(
+        long = "trusted-peer",
+        help = "Endpoint id of trusted peer for auto download (can be repeated)"
+    )]
+    trusted_peers: Vec<EndpointId>,
     #[command(subcommand)]
     command: CliCommand,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum CliCommand {
     Start,
-    Join { ticket: String },
+    /// Join existing channel by ticket or short code
+    Join {
+        ticket: String,
+    },
+    /// Check network connectivity and print diagnostics
+    Doctor,
+    /// Manage identity keys in data directory
+    #[command(subcommand)]
+    Identity(identity::IdentityCommand),
+    /// Manage blob store of identity
+    #[command(subcommand)]
+    Store(store::StoreCommand),
+}
+
+impl Args {
+    fn identity(&self) -> Result<&str> {
+        let name = self.identity.as_deref().context("--identity is required")?;
+        identity::check_name(name)?;
+        Ok(name)
+    }
 }
 
 async fn load_topic(data_dir: &Path, new_topic: bool) -> Result<TopicId> {
@@ -79,68 +253,774 @@ fn init_logging() {
 #[tokio::main]
 async fn main() -> Result<()> {
     init_logging();
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let CliCommand::Identity(ref cmd) = args.command {
+        return identity::run_command(&args.data_dir, cmd).await;
+    }
+    let config = config::load_config(&args.data_dir, args.identity.as_deref()).await?;
+    args.apply_config(config);
+    if let CliCommand::Doctor = args.command {
+        return diagnostics::doctor(&args).await;
+    }
+    if args.identity.is_none() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--identity is required",
+            )
+            .exit();
+    }
+    if let CliCommand::Store(ref cmd) = args.command {
+        let name = args.identity()?;
+        // opening store would create directory for mistyped identity
+        if !fs::try_exists(identity::identity_file(&args.data_dir, name)).await? {
+            anyhow::bail!("Identity {name} does not exist");
+        }
+        let store = context::init_store(&args.data_dir, name, context::gc_interval(&args)).await?;
+        let store_path = context::store_path(&args.data_dir, name);
+        let references = store::References::load(&store_path).await?;
+        let res = store::run_command(&store, &store_path, &references, cmd).await;
+        store.shutdown().await?;
+        for line in res? {
+            println!("{line}");
+        }
+        return Ok(());
+    }
 
-    let (topic, endpoints) = match args.command {
+    let (topic, endpoints, room) = match args.command {
         CliCommand::Start => {
             let topic = load_topic(&args.data_dir, args.new_topic).await?;
             let endpoints: Vec<EndpointAddr> = vec![];
 
-            (topic, endpoints)
+            (topic, endpoints, args.room.clone())
         }
         CliCommand::Join { ref ticket } => {
+            let from_code = rendezvous::is_code(ticket);
+            let ticket = if from_code {
+                println!("Resolving code {ticket}");
+                rendezvous::resolve(&args, ticket).await?
+            } else {
+                ticket.clone()
+            };
             let ticket: Ticket = ticket.parse().context("Invalid ticket")?;
-            (ticket.topic, ticket.endpoints)
+            ticket.verify().context("Ticket refused")?;
+            match &ticket.inviter {
+                Some(inviter) => println!(
+                    "Invited by {} ({}) to room {}",
+                    inviter.name,
+                    inviter.id.fmt_short(),
+                    ticket.room.as_deref().unwrap_or("<unnamed>")
+                ),
+                // signature could have been stripped to remove expiry
+                None => println!("Warning: unsigned invite, inviter not verified"),
+            }
+            // anyone who saw the code could offer own ticket
+            if from_code {
+                let origin = match &ticket.inviter {
+                    Some(inviter) => format!("inviter {}", inviter.id),
+                    None => {
+                        let ids: Vec<_> = ticket
+                            .endpoints
+                            .iter()
+                            .map(|ep| ep.id.to_string())
+                            .collect();
+                        format!("unsigned ticket with endpoints {}", ids.join(", "))
+                    }
+                };
+                if !confirm(&format!("Code resolved to {origin}, join?"))? {
+                    anyhow::bail!("Joining not confirmed");
+                }
+            }
+            (
+                ticket.topic,
+                ticket.endpoints,
+                args.room.clone().or(ticket.room),
+            )
+        }
+        CliCommand::Identity(_) | CliCommand::Doctor | CliCommand::Store(_) => {
+            unreachable!("Identity, doctor and store commands are handled above")
         }
     };
     let (output_sender, output_receiver) = tokio::sync::mpsc::channel(8);
     let (input_sender, input_receiver) = tokio::sync::mpsc::channel::<Command>(1);
-    let context = Context::new(&args, topic, endpoints, output_sender, input_sender).await?;
+    let context = Context::new(&args, topic, endpoints, room, output_sender, input_sender).await?;
+    report_bound_sockets(&args, context.endpoint());
 
     run(context, output_receiver, input_receiver).await?;
     Ok(())
 }
 
-async fn share_file(context: Context, file: &str) -> Result<()> {
-    let tag = context
-        .store()
-        .add_path(std::path::absolute(&file)?)
-        .await?;
-    let ticket = BlobTicket::new(context.endpoint().id().into(), tag.hash, tag.format);
-    let ticket = ticket.to_string();
-    output!(context, "!! Ticket for {}: {}", file, ticket);
+/// Asks for confirmation on terminal, only `y` or `yes` confirms
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{prompt} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Creates unique path for temporary file in data directory
+async fn temp_file(context: &Context) -> Result<PathBuf> {
+    let dir = context.data_dir().join("tmp");
+    fs::create_dir_all(&dir).await?;
+    Ok(dir.join(uuid::Uuid::new_v4().to_string()))
+}
+
+fn report_bound_sockets(args: &Args, endpoint: &Endpoint) {
+    let bound = endpoint.bound_sockets();
+    let bound_list: Vec<_> = bound.iter().map(|addr| addr.to_string()).collect();
+    println!("Bound to: {}", bound_list.join(", "));
+    for requested in args.bind.iter().filter(|addr| addr.port() != 0) {
+        let found = bound
+            .iter()
+            .any(|addr| addr.port() == requested.port() && addr.is_ipv4() == requested.is_ipv4());
+        if !found {
+            println!("Warning: cannot bind to {requested}, random port was used instead");
+        }
+    }
+}
+
+async fn share_file(
+    context: Context,
+    file: &str,
+    password: Option<String>,
+    reference: bool,
+) -> Result<Hash> {
+    let path = std::path::absolute(file)?;
+    let metadata = fs::metadata(&path).await?;
+    let tag = if let Some(password) = password.as_ref() {
+        let encrypted = temp_file(&context).await?;
+        let (input, output, password) = (path.clone(), encrypted.clone(), password.clone());
+        let res = async {
+            tokio::task::spawn_blocking(move || crypto::encrypt_file(&input, &output, &password))
+                .await??;
+            anyhow::Ok(context.store().add_path(&encrypted).await?)
+        }
+        .await;
+        fs::remove_file(&encrypted).await.ok();
+        res?
+    } else {
+        let mode = if reference {
+            ImportMode::TryReference
+        } else {
+            ImportMode::Copy
+        };
+        context
+            .store()
+            .add_path_with_opts(AddPathOptions {
+                path: path.clone(),
+                format: BlobFormat::Raw,
+                mode,
+            })
+            .await?
+    };
+    store::tag_shared(context.store(), &tag).await?;
+    if reference {
+        if password.is_some() {
+            output!(
+                context,
+                "!! Password protected files are always copied into store"
+            );
+        } else {
+            let reference = store::SharedReference::new(tag.hash, path).await?;
+            context.references().add(reference).await?;
+        }
+    }
     let file_name = Path::new(file)
         .file_name()
         .ok_or_else(|| anyhow!("Wrong file name"))?
         .to_str()
         .ok_or_else(|| anyhow!("File name is not UTF-8"))?;
-    let msg = Message::new_message(format!("Sharing {file_name} with ticket {ticket}"));
+    let info = FileInfo::from_metadata(file_name.to_string(), &metadata, password.is_some());
+    let blob_ticket = BlobTicket::new(context.endpoint().id().into(), tag.hash, tag.format);
+    let protected = if info.protected {
+        ", password protected"
+    } else {
+        ""
+    };
+    let size = store::format_size(info.size);
+    let ticket = FileTicket::new(blob_ticket, info).to_string();
+    output!(context, "!! Ticket for {}: {}", file, ticket);
+    if context.show_qr() {
+        show_qr(&context, &ticket, &format!("qr-{}", tag.hash)).await?;
+    }
+    // plain chat message, so peers with older versions see the share too
+    let msg = Message::new_message(format!(
+        "Sharing {file_name} ({size}{protected}) with ticket {ticket}"
+    ));
     context.send_message(msg).await;
 
+    Ok(tag.hash)
+}
+
+/// Prints ticket as QR code and optionally saves it as PNG `name` in data directory
+async fn show_qr(context: &Context, ticket: &str, name: &str) -> Result<()> {
+    let file = context.data_dir().join(format!("{name}.png"));
+    let png = context.qr_png().then_some(file.as_path());
+    output!(context, "{}", qr::ticket_qr(ticket, png)?);
+    if png.is_some() {
+        output!(context, "!! QR code saved to {}", file.display());
+    }
     Ok(())
 }
 
-async fn download_ticket(context: Context, ticket: &str, output_file: Option<&str>) -> Result<()> {
-    let ticket: BlobTicket = ticket.parse()?;
-    let output_file = output_file.map(PathBuf::from).unwrap_or_else(|| {
+async fn qr_ticket(context: &Context, ticket: Option<String>) -> Result<()> {
+    let (ticket, name) = match ticket {
+        None => (write_ticket(context).await?, "chat_ticket".to_string()),
+        Some(ticket) => {
+            if let Ok(file_ticket) = ticket.parse::<FileTicket>() {
+                let name = format!("qr-{}", file_ticket.hash());
+                (ticket, name)
+            } else if ticket.parse::<Ticket>().is_ok() {
+                // own invite is chat_ticket, do not overwrite it
+                let name = format!("qr-chat-{}", Hash::new(ticket.to_ascii_lowercase()));
+                (ticket, name)
+            } else {
+                anyhow::bail!("Invalid ticket");
+            }
+        }
+    };
+    show_qr(context, &ticket, &name).await
+}
+
+async fn code_ticket(context: &Context, ticket: Option<String>) -> Result<()> {
+    let ticket = match ticket {
+        None => write_ticket(context).await?,
+        Some(ticket) => {
+            if ticket.parse::<FileTicket>().is_err() && ticket.parse::<Ticket>().is_err() {
+                anyhow::bail!("Invalid ticket");
+            }
+            ticket
+        }
+    };
+    let code = rendezvous::offer(context.args(), ticket).await?;
+    output!(
+        context,
+        "!! Code (valid for {} minutes): {}",
+        rendezvous::CODE_TTL.as_secs() / 60,
+        code
+    );
+    Ok(())
+}
+
+async fn network_info(context: &Context) {
+    let peers: Vec<_> = context
+        .peers()
+        .neighbors()
+        .into_iter()
+        .map(|id| (context.peers().friendly_name(&id), id))
+        .collect();
+    output!(context, "!! Endpoint ID: {}", context.endpoint().id());
+    for line in diagnostics::network_report(context.args(), context.endpoint(), &peers) {
+        output!(context, "!! {}", line);
+    }
+}
+
+async fn download_ticket(
+    context: Context,
+    ticket: &str,
+    output_file: Option<&str>,
+    password: Option<String>,
+    force: bool,
+) -> Result<()> {
+    let FileTicket { blob: ticket, info } = if rendezvous::is_code(ticket) {
+        output!(context, "!! Resolving code {ticket}");
+        let resolved = rendezvous::resolve(context.args(), ticket).await?;
+        let file_ticket: FileTicket = resolved.parse()?;
+        // anyone who saw the code could offer own ticket, so only peers from channel are trusted
+        let provider = file_ticket.blob.addr().id;
+        if context.peers().find_by_id(&provider).is_none() {
+            output!(
+                context,
+                "!! Code {ticket} resolved to file from unknown peer {provider}, download it with #download {resolved} if you trust it"
+            );
+            return Ok(());
+        }
+        output!(
+            context,
+            "!! Code {ticket} resolved to file from {}",
+            context.peers().friendly_name(&provider)
+        );
+        file_ticket
+    } else {
+        ticket.parse()?
+    };
+    // plain blob tickets carry no metadata, it may be known from chat
+    let info = info.or_else(|| context.file_info(&ticket.hash()));
+    let downloads_dir = context.downloads_dir();
+    // default name comes from peer, so it is sanitized and never overwrites existing file
+    let (mut output_file, default_name) = match output_file {
+        Some(file) => (std::path::absolute(file)?, None),
+        None => {
+            let name = info
+                .as_ref()
+                .and_then(|info| files::sanitize_file_name(&info.name))
+                .unwrap_or_else(|| ticket.hash().to_string());
+            fs::create_dir_all(&downloads_dir).await?;
+            (files::unique_path(&downloads_dir, &name).await?, Some(name))
+        }
+    };
+    if !force && fs::try_exists(&output_file).await? {
+        anyhow::bail!(
+            "File {} already exists, use --force to overwrite it",
+            output_file.display()
+        );
+    }
+    if let Some(dir) = output_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+
+    if context.limits().is_limited(Direction::Download) {
+        fetch_throttled(&context, &ticket).await?;
+    } else {
         context
-            .data_dir()
-            .join("downloads")
-            .join(ticket.hash().to_string())
-    });
-    let output_file = std::path::absolute(output_file)?;
+            .downloader()
+            .download(ticket.hash_and_format(), Some(ticket.addr().id))
+            .await?;
+    }
+    store::tag_downloaded(context.store(), ticket.hash_and_format()).await?;
+    context.access_log().touch(ticket.hash()).await?;
+
+    let export_mode = match password {
+        // encrypted content is exported only to temporary file
+        Some(_) => ExportMode::Copy,
+        None => context.args().export_mode.unwrap_or_default(),
+    };
+    // store keeps referencing exported file, so it is written to target directly
+    let direct = export_mode == ExportMode::Reference;
+    if direct && !force {
+        // placeholder keeps target from being taken meanwhile, export then replaces it
+        loop {
+            match files::claim(&output_file).await {
+                Ok(()) => break,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::AlreadyExists && default_name.is_some() =>
+                {
+                    let name = default_name.as_deref().unwrap_or_default();
+                    output_file = files::unique_path(&downloads_dir, name).await?;
+                }
+                Err(e) => return Err(anyhow!("Cannot write {}: {e}", output_file.display())),
+            }
+        }
+    }
+    let target = match direct {
+        true => output_file.clone(),
+        false => files::part_path(&output_file),
+    };
+    let protected = info.as_ref().map(|info| info.protected);
+    let res = export_download(&context, &ticket, &target, password, protected, export_mode).await;
+    let method = match res {
+        Ok(method) => method,
+        Err(e) => {
+            // forced direct export goes to existing file, which must be kept
+            if !(direct && force) {
+                fs::remove_file(&target).await.ok();
+            }
+            return Err(e);
+        }
+    };
+    if context.args().preserve_attributes
+        && let Some(info) = info.as_ref()
+        && let Err(e) = files::apply_attributes(&target, info).await
+    {
+        output!(context, "!! Cannot set file attributes: {e}");
+    }
+    if !direct {
+        loop {
+            match files::persist(&target, &output_file, force).await {
+                Ok(()) => break,
+                // file with same name appeared meanwhile, e.g. from parallel download
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::AlreadyExists && default_name.is_some() =>
+                {
+                    let name = default_name.as_deref().unwrap_or_default();
+                    output_file = files::unique_path(&downloads_dir, name).await?;
+                }
+                Err(e) => {
+                    fs::remove_file(&target).await.ok();
+                    return Err(anyhow!("Cannot write {}: {e}", output_file.display()));
+                }
+            }
+        }
+    }
+    output!(
+        context,
+        "!! Downloaded to {} ({method})",
+        output_file.display()
+    );
+    if method == "referenced" {
+        output!(
+            context,
+            "!! Store now serves {} from this file, do not modify or delete it while it is in store",
+            ticket.hash().fmt_short()
+        );
+    }
+    if let BlobStatus::Complete { size } = context.store().blobs().status(ticket.hash()).await? {
+        let encrypted = if method == "decrypted" {
+            " of encrypted content"
+        } else {
+            ""
+        };
+        output!(
+            context,
+            "!! Verified BLAKE3 hash {}{}, size {} ({} bytes)",
+            ticket.hash(),
+            encrypted,
+            store::format_size(size),
+            size
+        );
+    }
+    enforce_store_quota(&context).await;
+    Ok(())
+}
+
+/// Short name of download for queue listing
+fn download_label(context: &Context, ticket: &str) -> String {
+    match ticket.parse::<FileTicket>() {
+        Ok(FileTicket { blob, info }) => match info.or_else(|| context.file_info(&blob.hash())) {
+            Some(info) => info.name,
+            None => blob.hash().fmt_short().to_string(),
+        },
+        // short code
+        Err(_) => ticket.to_string(),
+    }
+}
+
+/// Starts queued downloads as slots in queue become free
+async fn download_queue_loop(context: Context) {
+    loop {
+        let job = context.downloads().next().await;
+        let context = context.clone();
+        tokio::spawn(async move {
+            if job.queued {
+                output!(context, "!! Starting download #{} ({})", job.id, job.label);
+            }
+            let res = download_ticket(
+                context.clone(),
+                &job.ticket,
+                job.output_file.as_deref(),
+                job.password,
+                job.force,
+            )
+            .await;
+            context.downloads().finish(job.id);
+            if let Err(e) = res {
+                output!(context, "!! Error downloading file: {e}");
+            }
+        });
+    }
+}
 
-    let downloader = context.downloader();
-    downloader
-        .download(ticket.hash(), Some(ticket.addr().id))
+/// Downloads blob directly from ticket provider, reading it only as fast as limits allow
+///
+/// Slow reading propagates to sender through QUIC flow control.
+async fn fetch_throttled(context: &Context, ticket: &BlobTicket) -> Result<()> {
+    let peer = ticket.addr().id;
+    let connection = context
+        .endpoint()
+        .connect(ticket.addr().clone(), iroh_blobs::ALPN)
         .await?;
+    let mut progress = context
+        .store()
+        .remote()
+        .fetch(connection, ticket.hash_and_format())
+        .stream();
+    let mut received = 0;
+    while let Some(item) = progress.next().await {
+        match item {
+            GetProgressItem::Progress(bytes) => {
+                context
+                    .limits()
+                    .throttle(Direction::Download, Some(peer), bytes - received)
+                    .await;
+                received = bytes;
+            }
+            GetProgressItem::Done(_) => return Ok(()),
+            GetProgressItem::Error(e) => return Err(e.into()),
+        }
+    }
+    anyhow::bail!("Download ended without result")
+}
+
+/// Handles events of blobs provider
+///
+/// Served blobs count as used for store quota, throttling requests are served according
+/// to upload limits.
+async fn provider_events_loop(
+    mut receiver: tokio::sync::mpsc::Receiver<ProviderMessage>,
+    limits: Limits,
+    access_log: AccessLog,
+) {
+    let mut connections = HashMap::new();
+    while let Some(msg) = receiver.recv().await {
+        match msg {
+            ProviderMessage::ClientConnectedNotify(msg) => {
+                if let Some(id) = msg.endpoint_id {
+                    connections.insert(msg.connection_id, id);
+                }
+            }
+            ProviderMessage::ConnectionClosed(msg) => {
+                connections.remove(&msg.inner.connection_id);
+            }
+            ProviderMessage::GetRequestReceivedNotify(msg) => {
+                touch_served(&access_log, vec![msg.inner.request.hash]);
+            }
+            ProviderMessage::GetManyRequestReceivedNotify(msg) => {
+                touch_served(&access_log, msg.inner.request.hashes.clone());
+            }
+            // interception stays on, so limits can be set at runtime
+            ProviderMessage::Throttle(msg) if !limits.is_limited(Direction::Upload) => {
+                msg.tx.send(Ok(())).await.ok();
+            }
+            ProviderMessage::Throttle(msg) => {
+                let peer = connections.get(&msg.inner.connection_id).copied();
+                let limits = limits.clone();
+                tokio::spawn(async move {
+                    limits
+                        .throttle(Direction::Upload, peer, msg.inner.size)
+                        .await;
+                    msg.tx.send(Ok(())).await.ok();
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+fn touch_served(access_log: &AccessLog, hashes: Vec<Hash>) {
+    let access_log = access_log.clone();
+    tokio::spawn(async move {
+        if let Err(e) = access_log.touch_tracked(&hashes).await {
+            tracing::warn!("Cannot record access of served blobs: {e}");
+        }
+    });
+}
+
+/// Hashes local file and compares it with hash of blob ticket or given hash
+async fn verify_file(context: &Context, file: &str, expected: &str) -> Result<()> {
+    let (expected, info): (Hash, _) = match expected.parse::<FileTicket>() {
+        Ok(ticket) => (ticket.hash(), ticket.info),
+        Err(_) => (
+            expected
+                .parse()
+                .map_err(|_| anyhow!("{expected} is neither blob ticket nor hash"))?,
+            None,
+        ),
+    };
+    // hash of protected share is hash of encrypted content, never of decrypted file
+    if info
+        .or_else(|| context.file_info(&expected))
+        .is_some_and(|info| info.protected)
+    {
+        anyhow::bail!(
+            "{expected} is password protected share, its hash applies to encrypted content, decrypted file cannot be verified"
+        );
+    }
+    let (hash, size) = files::hash_file(Path::new(file)).await?;
+    if hash == expected {
+        output!(
+            context,
+            "!! OK: {file} matches {hash} ({})",
+            store::format_size(size)
+        );
+    } else {
+        output!(
+            context,
+            "!! MISMATCH: {file} has hash {hash}, expected {expected}"
+        );
+    }
+    Ok(())
+}
+
+/// Checks encryption header and size of blob in store
+///
+/// Plain file can start with the same header, so result is only a guess.
+async fn blob_is_encrypted(context: &Context, hash: Hash) -> Result<bool> {
+    let BlobStatus::Complete { size } = context.store().blobs().status(hash).await? else {
+        return Ok(false);
+    };
+    if !crypto::is_encrypted_len(size) {
+        return Ok(false);
+    }
+    let mut header = Vec::with_capacity(crypto::MAGIC_LEN);
     context
         .store()
         .blobs()
-        .export(ticket.hash(), &output_file)
+        .reader(hash)
+        .take(crypto::MAGIC_LEN as u64)
+        .read_to_end(&mut header)
         .await?;
-    output!(context, "!! Downloaded to {}", output_file.display());
-    Ok(())
+    Ok(crypto::has_header(&header))
+}
+
+/// Exports downloaded blob to file, decrypting it with password, returns export method
+async fn export_download(
+    context: &Context,
+    ticket: &BlobTicket,
+    file: &Path,
+    password: Option<String>,
+    protected: Option<bool>,
+    export_mode: ExportMode,
+) -> Result<&'static str> {
+    if let Some(password) = password {
+        let encrypted = temp_file(context).await?;
+        store::export(context.store(), ticket.hash(), &encrypted, ExportMode::Copy).await?;
+        let (input, output) = (encrypted.clone(), file.to_path_buf());
+        let res =
+            tokio::task::spawn_blocking(move || crypto::decrypt_file(&input, &output, &password))
+                .await;
+        fs::remove_file(&encrypted).await.ok();
+        res??;
+        Ok("decrypted")
+    } else {
+        match protected {
+            Some(true) => {
+                anyhow::bail!("File is password protected, download it with #download --password")
+            }
+            Some(false) => {}
+            // without metadata it is exported as is, plain file may look encrypted
+            None => {
+                if blob_is_encrypted(context, ticket.hash()).await? {
+                    output!(
+                        context,
+                        "!! File {} looks password protected, it is saved as is, download it with #download --password to decrypt",
+                        file.display()
+                    );
+                }
+            }
+        }
+        store::export(context.store(), ticket.hash(), file, export_mode).await
+    }
+}
+
+async fn enforce_store_quota(context: &Context) {
+    let Some(quota) = context.store_quota() else {
+        return;
+    };
+    match store::enforce_quota(context.store(), context.access_log(), quota).await {
+        Ok(evicted) if !evicted.is_empty() => output!(
+            context,
+            "!! Store over quota {}, evicted {} downloaded blobs",
+            store::format_size(quota),
+            evicted.len()
+        ),
+        Ok(_) => {}
+        Err(e) => output!(context, "!! Error enforcing store quota: {e}"),
+    }
+}
+
+const REFERENCE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stops sharing files shared by reference, when they change or disappear
+async fn reference_watch_loop(context: Context) {
+    let mut interval = tokio::time::interval(REFERENCE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for reference in context.references().list().await {
+            let Some(reason) = reference.check().await else {
+                continue;
+            };
+            let res = async {
+                store::unshare(context.store(), &reference.hash).await?;
+                context.references().remove(&reference.hash).await
+            }
+            .await;
+            match res {
+                Ok(_) => output!(
+                    context,
+                    "!! Shared file {} {}, stopped sharing it",
+                    reference.path.display(),
+                    reason
+                ),
+                Err(e) => output!(context, "!! Error unsharing {}: {e}", reference.hash),
+            }
+        }
+    }
+}
+
+const WATCH_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shares new and changed files in watched directory, previous versions are unshared
+async fn watch_loop(context: Context, dir: PathBuf) {
+    let debounce = context
+        .args()
+        .watch_debounce
+        .unwrap_or(DEFAULT_WATCH_DEBOUNCE);
+    let res = async {
+        // shared files are recorded by absolute path
+        let dir = std::path::absolute(&dir)?;
+        fs::create_dir_all(&dir).await?;
+        let debounce = Duration::from_secs(debounce);
+        FolderWatch::load(dir, debounce, &context.store_path()).await
+    }
+    .await;
+    let mut watch = match res {
+        Ok(watch) => watch,
+        Err(e) => {
+            output!(context, "!! Cannot watch {}: {e}", dir.display());
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(WATCH_SCAN_INTERVAL);
+    // report failing scan only once, not every second
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let files = match watch.scan().await {
+            Ok(files) => {
+                failing = false;
+                files
+            }
+            Err(e) => {
+                if !failing {
+                    output!(context, "!! Cannot scan {}: {e}", watch.dir().display());
+                }
+                failing = true;
+                continue;
+            }
+        };
+        for path in files {
+            let file = path.to_string_lossy();
+            let reference = context.args().reference_imports;
+            let res = async {
+                let hash = share_file(context.clone(), &file, None, reference).await?;
+                if let Some(previous) = watch.record(&path, hash).await? {
+                    store::unshare(context.store(), &previous).await?;
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            match res {
+                Ok(_) => output!(context, "!! Watched file {file} was shared"),
+                Err(e) => output!(context, "!! Error sharing watched file {file}: {e}"),
+            }
+        }
+    }
+}
+
+/// Periodically checks store quota, shares can grow the store too
+async fn quota_loop(context: Context) {
+    let mut interval = tokio::time::interval(context::gc_interval(context.args()));
+    loop {
+        interval.tick().await;
+        enforce_store_quota(&context).await;
+    }
+}
+
+const ACCESS_LOG_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Periodically writes access times of downloaded blobs, remaining changes are written on quit
+async fn access_log_loop(context: Context) {
+    let mut interval = tokio::time::interval(ACCESS_LOG_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = context.access_log().flush().await {
+            tracing::warn!("Cannot save access log: {e}");
+        }
+    }
 }
 
 async fn run(
@@ -152,7 +1032,20 @@ async fn run(
 
     // Blobs config
 
-    let blobs = BlobsProtocol::new(context.store(), None);
+    let mask = EventMask {
+        connected: ConnectMode::Notify,
+        get: RequestMode::Notify,
+        get_many: RequestMode::Notify,
+        throttle: ThrottleMode::Intercept,
+        ..EventMask::DEFAULT
+    };
+    let (events, events_receiver) = EventSender::channel(32, mask);
+    tokio::spawn(provider_events_loop(
+        events_receiver,
+        context.limits().clone(),
+        context.access_log().clone(),
+    ));
+    let blobs = BlobsProtocol::new(context.store(), Some(events));
 
     // Gossip config
     let gossip = Gossip::builder().spawn(endpoint.clone());
@@ -163,7 +1056,22 @@ async fn run(
 
     let (sender, receiver) = start_chat(&context, gossip).await?;
 
-    tokio::spawn(message_loop(receiver, context.clone()));
+    tokio::spawn(message_loop(receiver, sender.clone(), context.clone()));
+    tokio::spawn(reference_watch_loop(context.clone()));
+    tokio::spawn(download_queue_loop(context.clone()));
+    tokio::spawn(access_log_loop(context.clone()));
+    if context.args().export_mode == Some(ExportMode::Reference) {
+        output!(
+            context,
+            "!! Export mode is reference: downloaded files are kept by store, do not modify or delete them"
+        );
+    }
+    if let Some(dir) = context.args().watch_dir.clone() {
+        tokio::spawn(watch_loop(context.clone(), dir));
+    }
+    if context.store_quota().is_some() {
+        tokio::spawn(quota_loop(context.clone()));
+    }
 
     let mut line_reader = rustyline::DefaultEditor::new()?;
     let printer: Box<dyn rustyline::ExternalPrinter + Send> =
@@ -175,10 +1083,22 @@ async fn run(
 
     while let Some(cmd) = input_receiver.recv().await {
         match cmd {
-            Command::Share { file } => {
+            Command::Share {
+                file,
+                password,
+                reference,
+            } => {
+                let password = match password.into_given() {
+                    Ok(password) => password,
+                    Err(e) => {
+                        output!(context, "!! Error sharing file: {e}");
+                        continue;
+                    }
+                };
                 let context = context.clone();
+                let reference = reference || context.args().reference_imports;
                 tokio::spawn(async move {
-                    match share_file(context.clone(), &file).await {
+                    match share_file(context.clone(), &file, password, reference).await {
                         Ok(_) => output!(context, "!! File {file} was shared"),
                         Err(e) => output!(context, "!! Error sharing file: {e}"),
                     }
@@ -187,12 +1107,94 @@ async fn run(
             Command::Download {
                 ticket,
                 output_file,
+                password,
+                force,
+                priority,
             } => {
+                let password = match password.into_given() {
+                    Ok(password) => password,
+                    Err(e) => {
+                        output!(context, "!! Error downloading file: {e}");
+                        continue;
+                    }
+                };
+                let label = download_label(&context, &ticket);
+                let (id, position) = context.downloads().push(DownloadJob {
+                    id: 0,
+                    label: label.clone(),
+                    ticket,
+                    output_file,
+                    password,
+                    force,
+                    priority,
+                    queued: false,
+                });
+                if position > 0 {
+                    output!(
+                        context,
+                        "!! Download #{id} ({label}) queued at position {position}"
+                    );
+                }
+            }
+            Command::Queue(cmd) => match context.downloads().run_command(cmd) {
+                Ok(lines) => {
+                    for line in lines {
+                        output!(context, "!! {}", line);
+                    }
+                }
+                Err(e) => output!(context, "!! Queue error: {e}"),
+            },
+            Command::Invite => match write_ticket(&context).await {
+                Ok(ticket) => output!(context, "!! Ticket: {ticket}"),
+                Err(e) => output!(context, "!! Error creating ticket: {e}"),
+            },
+            Command::Qr { ticket } => {
+                if let Err(e) = qr_ticket(&context, ticket).await {
+                    output!(context, "!! Error showing QR code: {e}");
+                }
+            }
+            Command::Code { ticket } => {
                 let context = context.clone();
                 tokio::spawn(async move {
-                    match download_ticket(context.clone(), &ticket, output_file.as_deref()).await {
-                        Ok(_) => (),
-                        Err(e) => output!(context, "!! Error downloading file: {e}"),
+                    if let Err(e) = code_ticket(&context, ticket).await {
+                        output!(context, "!! Error creating code: {e}");
+                    }
+                });
+            }
+            Command::Net => network_info(&context).await,
+            Command::Limit(change) => {
+                if let Some(change) = change {
+                    context.limits().apply(change);
+                }
+                for line in context.limits().describe() {
+                    output!(context, "!! {}", line);
+                }
+            }
+            Command::Verify { file, expected } => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = verify_file(&context, &file, &expected).await {
+                        output!(context, "!! Error verifying file: {e}");
+                    }
+                });
+            }
+            Command::Store(cmd) => {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let res = store::run_command(
+                        context.store(),
+                        &context.store_path(),
+                        context.references(),
+                        &cmd,
+                    )
+                    .await;
+                    match res {
+                        Ok(lines) => {
+                            for line in lines {
+                                output!(context, "!! {}", line);
+                            }
+                        }
+                        Err(e) => output!(context, "!! Store error: {e}"),
                     }
                 });
             }
@@ -207,43 +1209,162 @@ async fn run(
         }
     }
     router.shutdown().await?;
+    context.access_log().flush().await?;
     Ok(())
 }
 
+/// Max. number of neighbors included in the ticket as bootstrap endpoints
+const TICKET_NEIGHBORS: usize = 4;
+
+/// Best known address of connected neighbor
+fn neighbor_addr(endpoint: &Endpoint, id: EndpointId) -> EndpointAddr {
+    let addr = EndpointAddr::new(id);
+    match endpoint.conn_type(id).map(|mut conn| conn.get()) {
+        Some(ConnectionType::Direct(ip)) => addr.with_ip_addr(ip),
+        Some(ConnectionType::Relay(relay)) => addr.with_relay_url(relay),
+        Some(ConnectionType::Mixed(ip, relay)) => addr.with_ip_addr(ip).with_relay_url(relay),
+        Some(ConnectionType::None) | None => addr,
+    }
+}
+
+fn create_ticket(context: &Context) -> Result<Ticket> {
+    let endpoint = context.endpoint();
+    let mut neighbors = context.peers().neighbors();
+    // prefer neighbors with lowest latency, unknown latency last
+    neighbors.sort_by_key(|id| endpoint.latency(*id).unwrap_or(Duration::MAX));
+    let endpoints = std::iter::once(endpoint.addr())
+        .chain(
+            neighbors
+                .into_iter()
+                .take(TICKET_NEIGHBORS)
+                .map(|id| neighbor_addr(endpoint, id)),
+        )
+        .collect();
+    Ticket::new(*context.topic_id(), endpoints)
+        .with_room(context.room().map(String::from))
+        .with_ttl(context.invite_ttl())
+        .sign(endpoint.secret_key(), context.display_name())
+}
+
+/// Creates fresh ticket and saves it to `chat_ticket.txt`
+async fn write_ticket(context: &Context) -> Result<String> {
+    let ticket = create_ticket(context)?.to_string();
+    fs::write(context.data_dir().join("chat_ticket.txt"), &ticket).await?;
+    Ok(ticket)
+}
+
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_JOIN_TIMEOUT: u64 = 120;
+
+/// Waits for joining the topic, periodically retries bootstrap endpoints and reports status
+async fn join_with_retry(
+    context: &Context,
+    sender: &GossipSender,
+    receiver: &mut GossipReceiver,
+    bootstrap: Vec<EndpointId>,
+) -> Result<()> {
+    let join_timeout =
+        Duration::from_secs(context.args().join_timeout.unwrap_or(DEFAULT_JOIN_TIMEOUT));
+    let start = tokio::time::Instant::now();
+    loop {
+        match tokio::time::timeout(JOIN_RETRY_INTERVAL, receiver.joined()).await {
+            Ok(res) => return Ok(res?),
+            Err(_) => {
+                let status =
+                    diagnostics::join_status(context.args(), context.endpoint(), &bootstrap);
+                if start.elapsed() >= join_timeout {
+                    anyhow::bail!(
+                        "Cannot join channel within {} s:\n{}",
+                        join_timeout.as_secs(),
+                        status.join("\n")
+                    );
+                }
+                println!(
+                    "Still waiting to join channel ({} s):",
+                    start.elapsed().as_secs()
+                );
+                for line in status {
+                    println!("  {line}");
+                }
+                sender.join_peers(bootstrap.clone()).await?;
+            }
+        }
+    }
+}
+
 async fn start_chat(
     context: &Context,
     gossip: Gossip,
-) -> Result<(iroh_gossip::api::GossipSender, GossipReceiver), anyhow::Error> {
+) -> Result<(GossipSender, GossipReceiver), anyhow::Error> {
     let topic = *context.topic_id();
     let topic_endpoints = context.topic_endpoints();
     let endpoint = context.endpoint();
-    let ticket = Ticket {
-        topic: topic,
-        endpoints: vec![endpoint.id().into()],
-    };
-    let ticket = ticket.to_string();
+    let ticket = write_ticket(context).await?;
     println!("Ticket: \n{}", ticket);
-    fs::write(context.data_dir().join("chat_ticket.txt"), ticket).await?;
+    if context.show_qr() {
+        let file = context.data_dir().join("chat_ticket.png");
+        let png = context.qr_png().then_some(file.as_path());
+        println!("{}", qr::ticket_qr(&ticket, png)?);
+    }
     if topic_endpoints.is_empty() {
         println!("Waiting somebody joins our channel")
     } else {
         println!("Waiting to join channel")
     }
-    let endpoint_ids: Vec<_> = topic_endpoints.iter().map(|ep| ep.id).collect();
-    let (sender, receiver) = gossip
-        .subscribe_and_join(topic, endpoint_ids)
-        .await?
-        .split();
+    let endpoint_ids: Vec<_> = topic_endpoints
+        .iter()
+        .map(|ep| ep.id)
+        .filter(|id| *id != endpoint.id())
+        .collect();
+    let (sender, mut receiver) = gossip.subscribe(topic, endpoint_ids.clone()).await?.split();
+    if endpoint_ids.is_empty() {
+        receiver.joined().await?;
+    } else {
+        join_with_retry(context, &sender, &mut receiver, endpoint_ids).await?;
+    }
+    for id in receiver.neighbors() {
+        context.peers().neighbor_up(id);
+    }
     println!("Connected - start chat below");
     println!("------------------------------------");
     let data: Vec<u8> =
-        Message::new_intro(context.identity().into()).sign_and_encode(endpoint.secret_key())?;
+        Message::new_intro(context.display_name().into()).sign_and_encode(endpoint.secret_key())?;
     sender.broadcast(data.into()).await?;
+    if !context.handovers().is_empty() {
+        let data: Vec<u8> = Message::new_key_rotation(context.handovers().to_vec())
+            .sign_and_encode(endpoint.secret_key())?;
+        sender.broadcast(data.into()).await?;
+    }
     Ok((sender, receiver))
 }
 
-async fn message_loop(mut receiver: GossipReceiver, context: Context) -> Result<()> {
+const REJOIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically tries to rejoin the topic through all known peers and the original bootstrap endpoints
+async fn rejoin_loop(sender: GossipSender, context: Context) {
+    let own_id = context.endpoint().id();
+    loop {
+        let mut candidates = context.peers().known_peers();
+        candidates.extend(context.topic_endpoints().iter().map(|ep| ep.id));
+        candidates.retain(|id| *id != own_id);
+        candidates.sort();
+        candidates.dedup();
+        if !candidates.is_empty()
+            && let Err(e) = sender.join_peers(candidates).await
+        {
+            error!("Failed to rejoin channel: {}", e);
+        }
+        tokio::time::sleep(REJOIN_INTERVAL).await;
+    }
+}
+
+async fn message_loop(
+    mut receiver: GossipReceiver,
+    sender: GossipSender,
+    context: Context,
+) -> Result<()> {
     let directory = context.peers();
+    let mut rejoin: Option<tokio::task::JoinHandle<()>> = None;
     while let Some(event) = receiver.next().await {
         let event = event?;
         match event {
@@ -259,6 +1380,19 @@ async fn message_loop(mut receiver: GossipReceiver, context: Context) -> Result<
                     MessageBody::Message { text } => {
                         let name = directory.friendly_name(&from);
                         output!(context, "<< {}: {}", name, text);
+                        // file tickets carry metadata, plain tickets can be downloaded with it later
+                        for ticket in text.split_whitespace() {
+                            if let Ok(FileTicket {
+                                blob,
+                                info: Some(info),
+                            }) = ticket.parse::<FileTicket>()
+                            {
+                                context.add_file_info(blob.hash(), info, from);
+                            }
+                        }
+                        if context.should_auto_download(&from) {
+                            auto_download(&context, &text).await;
+                        }
                     }
                     MessageBody::Intro { name } => {
                         let existing = directory.add_peer(from, name.clone());
@@ -277,16 +1411,56 @@ async fn message_loop(mut receiver: GossipReceiver, context: Context) -> Result<
                             output!(context, "<< User {} joined with name {}", short_id, name);
                         }
                     }
+                    MessageBody::KeyRotation { chain } => {
+                        if let Err(e) = KeyHandover::verify_chain(&chain, &from) {
+                            error!("Invalid key rotation from {}: {}", from.fmt_short(), e);
+                            continue;
+                        }
+                        for handover in &chain {
+                            let trusted = directory.is_trusted(&handover.old);
+                            let name = directory.rotate_key(&handover.old, handover.new);
+                            let user = match (name, trusted) {
+                                (Some(name), _) => format!("User {name}"),
+                                (None, true) => "Trusted peer".to_string(),
+                                (None, false) => continue,
+                            };
+                            output!(
+                                context,
+                                "<< {} rotated key from {} to {}",
+                                user,
+                                handover.old.fmt_short(),
+                                handover.new.fmt_short()
+                            );
+                        }
+                    }
                 }
             }
             Event::NeighborUp(id) => {
+                directory.neighbor_up(id);
+                if let Some(task) = rejoin.take() {
+                    task.abort();
+                    output!(
+                        context,
+                        "!! Rejoined channel via {}",
+                        directory.friendly_name(&id)
+                    );
+                }
                 output!(context, "<< New user {} just joined", id.fmt_short());
-                let intro = Message::new_intro(context.identity().into());
+                let intro = Message::new_intro(context.display_name().into());
                 context.send_message(intro).await;
+                if !context.handovers().is_empty() {
+                    let rotation = Message::new_key_rotation(context.handovers().to_vec());
+                    context.send_message(rotation).await;
+                }
             }
             Event::NeighborDown(id) => {
+                directory.neighbor_down(&id);
                 let name = directory.friendly_name(&id);
                 output!(context, "<< User {} just left", name);
+                if directory.neighbors().is_empty() && rejoin.is_none() {
+                    output!(context, "!! No neighbors left, trying to rejoin channel");
+                    rejoin = Some(tokio::spawn(rejoin_loop(sender.clone(), context.clone())));
+                }
             }
             Event::Lagged => {
                 output!(context, "<< Lagged - some messages were lost");
@@ -296,6 +1470,25 @@ async fn message_loop(mut receiver: GossipReceiver, context: Context) -> Result<
     Ok(())
 }
 
+/// Downloads all blob tickets found in the message
+async fn auto_download(context: &Context, text: &str) {
+    for word in text.split_whitespace() {
+        if word.parse::<FileTicket>().is_ok() {
+            output!(context, "!! Auto downloading {}", word);
+            context
+                .send_command(Command::Download {
+                    ticket: word.to_string(),
+                    output_file: None,
+                    password: Password::None,
+                    force: false,
+                    // files requested explicitly go first
+                    priority: Priority::Low,
+                })
+                .await;
+        }
+    }
+}
+
 fn output_loop(
     mut output_receiver: tokio::sync::mpsc::Receiver<String>,
     mut printer: Box<dyn rustyline::ExternalPrinter>,
@@ -323,7 +1516,12 @@ fn input_loop(
                 if !text.is_empty() {
                     let parsed: Result<Command, _> = text.parse();
                     match parsed {
-                        Ok(cmd) => {
+                        Ok(mut cmd) => {
+                            // line editor is not active here, so password prompt is not garbled
+                            if let Err(e) = cmd.prompt_password() {
+                                println!("!! {e}");
+                                continue;
+                            }
                             let is_end = matches!(cmd, Command::Quit);
                             sender.blocking_send(cmd)?;
                             if is_end {
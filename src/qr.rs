@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use anyhow::Result;
+use image::Luma;
+use qrcode::{QrCode, render::unicode::Dense1x2};
+
+/// Renders ticket as QR code from Unicode half blocks, optionally saves it also as PNG
+///
+/// Ticket is upper-cased, so QR code can use compact alphanumeric mode, parsing ignores case.
+pub fn ticket_qr(ticket: &str, png: Option<&Path>) -> Result<String> {
+    let code = QrCode::new(ticket.to_ascii_uppercase().as_bytes())?;
+    if let Some(file) = png {
+        save_png(&code, file)?;
+    }
+    Ok(render_unicode(&code))
+}
+
+/// Inverted for dark terminals
+fn render_unicode(code: &QrCode) -> String {
+    code.render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .quiet_zone(true)
+        .build()
+}
+
+fn save_png(code: &QrCode, file: &Path) -> Result<()> {
+    let image = code.render::<Luma<u8>>().min_dimensions(400, 400).build();
+    image.save(file)?;
+    Ok(())
+}
@@ -0,0 +1,322 @@
+//! Download queue
+//!
+//! Downloads wait in queue and only limited number of them runs at once. New job is queued
+//! after all jobs with same or higher priority, queued jobs can be reordered at runtime.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::Notify;
+
+pub const DEFAULT_PARALLEL_DOWNLOADS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            s => bail!("Unknown priority {s}, use low, normal or high"),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        f.write_str(name)
+    }
+}
+
+pub enum QueueCommand {
+    List,
+    /// Moves queued job to position (starting from 1)
+    Move {
+        id: u64,
+        position: usize,
+    },
+    /// Changes priority of queued job, it is queued again according to it
+    Priority {
+        id: u64,
+        priority: Priority,
+    },
+    Cancel {
+        id: u64,
+    },
+    /// Number of downloads running at once, 0 pauses queue
+    Parallel(usize),
+}
+
+pub struct DownloadJob {
+    /// Assigned by queue
+    pub id: u64,
+    /// Name shown in queue listing
+    pub label: String,
+    pub ticket: String,
+    pub output_file: Option<String>,
+    pub password: Option<String>,
+    pub force: bool,
+    pub priority: Priority,
+    /// Job had to wait for free slot
+    pub queued: bool,
+}
+
+struct QueueState {
+    pending: Vec<DownloadJob>,
+    /// Ids and labels of running jobs
+    running: Vec<(u64, String)>,
+    parallelism: usize,
+    next_id: u64,
+}
+
+impl QueueState {
+    fn position(&self, id: u64) -> Result<usize> {
+        match self.pending.iter().position(|job| job.id == id) {
+            Some(index) => Ok(index),
+            None if self.running.iter().any(|(running, _)| *running == id) => {
+                bail!("Download #{id} is already running")
+            }
+            None => bail!("No queued download #{id}"),
+        }
+    }
+
+    /// Inserts job after all jobs with same or higher priority, returns its index
+    fn insert(&mut self, job: DownloadJob) -> usize {
+        let index = self
+            .pending
+            .iter()
+            .position(|queued| queued.priority < job.priority)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(index, job);
+        index
+    }
+}
+
+#[derive(Clone)]
+pub struct DownloadQueue {
+    state: Arc<Mutex<QueueState>>,
+    changed: Arc<Notify>,
+}
+
+impl DownloadQueue {
+    pub fn new(parallelism: usize) -> Self {
+        DownloadQueue {
+            state: Arc::new(Mutex::new(QueueState {
+                pending: Vec::new(),
+                running: Vec::new(),
+                parallelism,
+                next_id: 1,
+            })),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Adds job to queue, returns its id and position (0 if it starts right away)
+    pub fn push(&self, mut job: DownloadJob) -> (u64, usize) {
+        let mut state = self.state.lock().unwrap();
+        job.id = state.next_id;
+        state.next_id += 1;
+        let free = state.parallelism.saturating_sub(state.running.len());
+        let index = state.insert(job);
+        let position = (index + 1).saturating_sub(free);
+        if position > 0 {
+            state.pending[index].queued = true;
+        }
+        let id = state.pending[index].id;
+        drop(state);
+        self.changed.notify_one();
+        (id, position)
+    }
+
+    /// Waits for next job which can run, job must be reported by [`DownloadQueue::finish`]
+    pub async fn next(&self) -> DownloadJob {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.running.len() < state.parallelism && !state.pending.is_empty() {
+                    let job = state.pending.remove(0);
+                    state.running.push((job.id, job.label.clone()));
+                    return job;
+                }
+            }
+            self.changed.notified().await;
+        }
+    }
+
+    pub fn finish(&self, id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .running
+            .retain(|(running, _)| *running != id);
+        self.changed.notify_one();
+    }
+
+    pub fn run_command(&self, cmd: QueueCommand) -> Result<Vec<String>> {
+        let mut state = self.state.lock().unwrap();
+        match cmd {
+            QueueCommand::List => {}
+            QueueCommand::Move { id, position } => {
+                let index = state.position(id)?;
+                let job = state.pending.remove(index);
+                let index = position.saturating_sub(1).min(state.pending.len());
+                state.pending.insert(index, job);
+            }
+            QueueCommand::Priority { id, priority } => {
+                let index = state.position(id)?;
+                let mut job = state.pending.remove(index);
+                job.priority = priority;
+                state.insert(job);
+            }
+            QueueCommand::Cancel { id } => {
+                let index = state.position(id)?;
+                let job = state.pending.remove(index);
+                return Ok(vec![format!("Download #{id} ({}) cancelled", job.label)]);
+            }
+            QueueCommand::Parallel(parallelism) => {
+                state.parallelism = parallelism;
+                self.changed.notify_one();
+            }
+        }
+        Ok(describe(&state))
+    }
+}
+
+fn describe(state: &QueueState) -> Vec<String> {
+    let mut lines = vec![format!(
+        "Downloads running: {}/{}{}",
+        state.running.len(),
+        state.parallelism,
+        if state.parallelism == 0 {
+            " (paused)"
+        } else {
+            ""
+        }
+    )];
+    lines.extend(
+        state
+            .running
+            .iter()
+            .map(|(id, label)| format!("  #{id} {label}")),
+    );
+    if !state.pending.is_empty() {
+        lines.push(format!("Queued: {}", state.pending.len()));
+        lines.extend(state.pending.iter().enumerate().map(|(index, job)| {
+            format!(
+                "  {}. #{} {} ({})",
+                index + 1,
+                job.id,
+                job.label,
+                job.priority
+            )
+        }));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(label: &str, priority: Priority) -> DownloadJob {
+        DownloadJob {
+            id: 0,
+            label: label.to_string(),
+            ticket: String::new(),
+            output_file: None,
+            password: None,
+            force: false,
+            priority,
+            queued: false,
+        }
+    }
+
+    fn pending(queue: &DownloadQueue) -> Vec<String> {
+        let state = queue.state.lock().unwrap();
+        state.pending.iter().map(|job| job.label.clone()).collect()
+    }
+
+    #[test]
+    fn push_reports_position_after_free_slots() {
+        let queue = DownloadQueue::new(2);
+        assert_eq!(queue.push(job("a", Priority::Normal)), (1, 0));
+        assert_eq!(queue.push(job("b", Priority::Normal)), (2, 0));
+        assert_eq!(queue.push(job("c", Priority::Normal)), (3, 1));
+        assert_eq!(queue.push(job("d", Priority::Normal)), (4, 2));
+        let state = queue.state.lock().unwrap();
+        let queued: Vec<bool> = state.pending.iter().map(|job| job.queued).collect();
+        assert_eq!(queued, [false, false, true, true]);
+    }
+
+    #[test]
+    fn jobs_are_inserted_by_priority() {
+        let queue = DownloadQueue::new(0);
+        queue.push(job("normal1", Priority::Normal));
+        queue.push(job("low", Priority::Low));
+        queue.push(job("high", Priority::High));
+        queue.push(job("normal2", Priority::Normal));
+        assert_eq!(pending(&queue), ["high", "normal1", "normal2", "low"]);
+    }
+
+    #[test]
+    fn queued_jobs_can_be_moved_and_cancelled() {
+        let queue = DownloadQueue::new(0);
+        for label in ["a", "b", "c"] {
+            queue.push(job(label, Priority::Normal));
+        }
+        queue
+            .run_command(QueueCommand::Move { id: 3, position: 1 })
+            .unwrap();
+        assert_eq!(pending(&queue), ["c", "a", "b"]);
+        queue
+            .run_command(QueueCommand::Move {
+                id: 3,
+                position: 10,
+            })
+            .unwrap();
+        assert_eq!(pending(&queue), ["a", "b", "c"]);
+        queue
+            .run_command(QueueCommand::Priority {
+                id: 3,
+                priority: Priority::High,
+            })
+            .unwrap();
+        assert_eq!(pending(&queue), ["c", "a", "b"]);
+        let lines = queue.run_command(QueueCommand::Cancel { id: 1 }).unwrap();
+        assert_eq!(lines, ["Download #1 (a) cancelled"]);
+        assert_eq!(pending(&queue), ["c", "b"]);
+        assert!(queue.run_command(QueueCommand::Cancel { id: 1 }).is_err());
+    }
+
+    #[tokio::test]
+    async fn running_jobs_cannot_be_changed() {
+        let queue = DownloadQueue::new(1);
+        queue.push(job("a", Priority::Normal));
+        queue.push(job("b", Priority::Normal));
+        let running = queue.next().await;
+        assert_eq!(running.label, "a");
+        let err = queue
+            .run_command(QueueCommand::Cancel { id: running.id })
+            .unwrap_err();
+        assert!(err.to_string().contains("already running"));
+        queue.finish(running.id);
+        assert_eq!(queue.next().await.label, "b");
+    }
+}
@@ -0,0 +1,162 @@
+//! Short wormhole-like codes for tickets
+//!
+//! Code is used to derive secret key of temporary rendezvous endpoint, which is published
+//! via the same discovery mechanisms as main endpoint and serves the full ticket
+//! for limited time. Only endpoint which knows the code can serve the ticket, as it must
+//! prove ownership of the derived key.
+//!
+//! Code has low entropy and anyone who sees it can host competing offer, so origin of resolved
+//! ticket must be shown and confirmed by user before it is used.
+//!
+//! Temporary endpoints bind on ports picked by system, configured bind addresses are left
+//! to the main endpoint, which can run at the same time.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context as _, Result};
+use iroh::{
+    EndpointAddr, SecretKey,
+    discovery::static_provider::StaticProvider,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, Router},
+};
+use tracing::debug;
+
+use crate::{Args, context::bind_endpoint};
+
+pub const ALPN: &[u8] = b"send-file/rendezvous/1";
+pub const CODE_TTL: Duration = Duration::from_secs(10 * 60);
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(30);
+const CODE_WORDS: usize = 2;
+const MAX_TICKET_SIZE: usize = 16 * 1024;
+
+pub fn generate_code() -> String {
+    let words = bip39::Language::English.word_list();
+    let mut code = (rand::random::<u16>() % 1000).to_string();
+    for _ in 0..CODE_WORDS {
+        code.push('-');
+        code.push_str(words[rand::random_range(0..words.len())]);
+    }
+    code
+}
+
+/// Checks if string looks like a code rather than full ticket
+pub fn is_code(s: &str) -> bool {
+    let mut parts = s.split('-');
+    let number_ok = parts
+        .next()
+        .map(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false);
+    let words: Vec<_> = parts.collect();
+    number_ok
+        && words.len() == CODE_WORDS
+        && words
+            .iter()
+            .all(|w| bip39::Language::English.find_word(w).is_some())
+}
+
+fn normalize(code: &str) -> String {
+    code.trim().to_ascii_lowercase()
+}
+
+fn code_key(code: &str) -> SecretKey {
+    let key = blake3::derive_key("send-file rendezvous code v1", normalize(code).as_bytes());
+    SecretKey::from_bytes(&key)
+}
+
+#[derive(Debug, Clone)]
+struct TicketProvider {
+    ticket: Arc<str>,
+}
+
+impl ProtocolHandler for TicketProvider {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let mut send = connection.open_uni().await?;
+        send.write_all(self.ticket.as_bytes())
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish().map_err(AcceptError::from_err)?;
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+/// Starts rendezvous endpoint serving the ticket for [`CODE_TTL`], returns the code
+pub async fn offer(args: &Args, ticket: String) -> Result<String> {
+    let code = generate_code();
+    let endpoint = bind_endpoint(args, &[], code_key(&code), StaticProvider::new()).await?;
+    let router = Router::builder(endpoint)
+        .accept(
+            ALPN,
+            TicketProvider {
+                ticket: ticket.into(),
+            },
+        )
+        .spawn();
+    let code_copy = code.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(CODE_TTL).await;
+        debug!("Code {code_copy} expired");
+        router.shutdown().await.ok();
+    });
+    Ok(code)
+}
+
+/// Finds rendezvous endpoint for the code and fetches the ticket from it
+///
+/// Anyone knowing the code could offer the ticket, so caller must confirm its origin.
+pub async fn resolve(args: &Args, code: &str) -> Result<String> {
+    let secret_key = SecretKey::generate(&mut rand::rng());
+    let endpoint = bind_endpoint(args, &[], secret_key, StaticProvider::new()).await?;
+    let addr = EndpointAddr::new(code_key(code).public());
+    let res = tokio::time::timeout(RESOLVE_TIMEOUT, async {
+        let connection = endpoint.connect(addr, ALPN).await?;
+        let mut recv = connection.accept_uni().await?;
+        let data = recv.read_to_end(MAX_TICKET_SIZE).await?;
+        connection.close(0u32.into(), b"done");
+        Ok(String::from_utf8(data)?)
+    })
+    .await
+    .context("Code not found (expired or peer offline)")
+    .and_then(|res| res);
+    endpoint.close().await;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_code_is_recognized() {
+        for _ in 0..100 {
+            let code = generate_code();
+            assert!(is_code(&code), "{code}");
+            assert_eq!(code.split('-').count(), CODE_WORDS + 1);
+        }
+    }
+
+    #[test]
+    fn tickets_are_not_codes() {
+        assert!(!is_code("123"));
+        assert!(!is_code("123-abandon"));
+        assert!(!is_code("123-abandon-ability-able"));
+        assert!(!is_code("abc-abandon-ability"));
+        assert!(!is_code("-abandon-ability"));
+        assert!(!is_code("123-abandon-notaword"));
+        assert!(!is_code(
+            "blobafyreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        ));
+    }
+
+    #[test]
+    fn code_key_ignores_case_and_whitespace() {
+        let code = generate_code();
+        let key = code_key(&code).public();
+        assert_eq!(
+            code_key(&format!(" {} ", code.to_uppercase())).public(),
+            key
+        );
+        assert_ne!(code_key(&generate_code()).public(), key);
+    }
+}
@@ -0,0 +1,623 @@
+//! Store management - listing and removing entries, disk usage
+//!
+//! Shared and downloaded blobs are kept under named tags, tag prefix records their origin.
+//! Blobs without any tag are deleted by garbage collection.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use clap::Subcommand;
+use futures_lite::StreamExt as _;
+use iroh_blobs::{
+    Hash, HashAndFormat,
+    api::{
+        Store,
+        blobs::{self, ExportOptions},
+        proto::{BlobStatus, ExportProgressItem, TagInfo},
+    },
+    store::fs::options::{InlineOptions, PathOptions},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+
+use crate::{config::ExportMode, files, watch};
+
+pub const SHARED_PREFIX: &str = "shared/";
+pub const DOWNLOADED_PREFIX: &str = "downloaded/";
+
+pub fn shared_tag(hash: &Hash) -> String {
+    format!("{SHARED_PREFIX}{hash}")
+}
+
+pub fn downloaded_tag(hash: &Hash) -> String {
+    format!("{DOWNLOADED_PREFIX}{hash}")
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum StoreCommand {
+    /// List blobs in store with their size and origin
+    List,
+    /// Remove blob (given by hash or its unique prefix), data is deleted by next garbage collection
+    #[command(alias = "rm")]
+    Remove { hash: String },
+    /// Show disk usage of store
+    #[command(alias = "du")]
+    Usage,
+}
+
+pub struct StoreEntry {
+    pub hash: Hash,
+    pub status: BlobStatus,
+    pub tags: Vec<String>,
+}
+
+impl StoreEntry {
+    pub fn size(&self) -> Option<u64> {
+        match self.status {
+            BlobStatus::Complete { size } => Some(size),
+            BlobStatus::Partial { size } => size,
+            BlobStatus::NotFound => None,
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.tags.iter().any(|tag| tag.starts_with(SHARED_PREFIX))
+    }
+
+    pub fn is_downloaded(&self) -> bool {
+        self.tags
+            .iter()
+            .any(|tag| tag.starts_with(DOWNLOADED_PREFIX))
+    }
+
+    fn origin(&self) -> String {
+        if self.tags.is_empty() {
+            return "untagged".into();
+        }
+        let mut origins: Vec<&str> = self
+            .tags
+            .iter()
+            .map(|tag| {
+                if tag.starts_with(SHARED_PREFIX) {
+                    "shared"
+                } else if tag.starts_with(DOWNLOADED_PREFIX) {
+                    "downloaded"
+                } else {
+                    tag.as_str()
+                }
+            })
+            .collect();
+        origins.sort();
+        origins.dedup();
+        origins.join(", ")
+    }
+}
+
+/// Lists all blobs in store together with tags pointing to them
+pub async fn entries(store: &Store) -> Result<Vec<StoreEntry>> {
+    let mut tags: BTreeMap<Hash, Vec<String>> = BTreeMap::new();
+    let mut tag_list = store.tags().list().await?;
+    while let Some(tag) = tag_list.next().await {
+        let tag = tag?;
+        tags.entry(tag.hash)
+            .or_default()
+            .push(String::from_utf8_lossy(tag.name.as_ref()).into_owned());
+    }
+    let mut hashes = store.blobs().list().hashes().await?;
+    let missing: Vec<Hash> = tags
+        .keys()
+        .filter(|hash| !hashes.contains(hash))
+        .copied()
+        .collect();
+    hashes.extend(missing);
+    let mut entries = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let status = store.blobs().status(hash).await?;
+        let tags = tags.remove(&hash).unwrap_or_default();
+        entries.push(StoreEntry { hash, status, tags });
+    }
+    Ok(entries)
+}
+
+/// Replaces automatic tag created on import with shared tag
+pub async fn tag_shared(store: &Store, tag: &TagInfo) -> Result<()> {
+    store
+        .tags()
+        .set(
+            shared_tag(&tag.hash),
+            HashAndFormat::new(tag.hash, tag.format),
+        )
+        .await?;
+    store.tags().delete(&tag.name).await?;
+    Ok(())
+}
+
+/// Tags content as downloaded, so it is kept in store
+///
+/// Format must match ticket, hash sequence tag protects also its children.
+pub async fn tag_downloaded(store: &Store, content: HashAndFormat) -> Result<()> {
+    store
+        .tags()
+        .set(downloaded_tag(&content.hash), content)
+        .await?;
+    Ok(())
+}
+
+/// Last access times of downloaded blobs, persisted in store directory
+///
+/// Changes are kept in memory and written by [`AccessLog::flush`], so serving blobs does not
+/// rewrite the file on every request.
+#[derive(Clone)]
+pub struct AccessLog {
+    store_path: PathBuf,
+    file: PathBuf,
+    times: Arc<Mutex<AccessTimes>>,
+}
+
+#[derive(Default)]
+struct AccessTimes {
+    times: HashMap<Hash, u64>,
+    dirty: bool,
+}
+
+fn unix_time(time: SystemTime) -> Result<u64> {
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+impl AccessLog {
+    pub async fn load(store_path: &Path) -> Result<Self> {
+        let file = store_path.join("access.bin");
+        let times = if fs::try_exists(&file).await? {
+            let entries: Vec<(Hash, u64)> = postcard::from_bytes(&fs::read(&file).await?)?;
+            entries.into_iter().collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(AccessLog {
+            store_path: store_path.to_path_buf(),
+            file,
+            times: Arc::new(Mutex::new(AccessTimes {
+                times,
+                dirty: false,
+            })),
+        })
+    }
+
+    /// Records that blob was used now
+    pub async fn touch(&self, hash: Hash) -> Result<()> {
+        let now = unix_time(SystemTime::now())?;
+        let mut times = self.times.lock().await;
+        times.times.insert(hash, now);
+        times.dirty = true;
+        Ok(())
+    }
+
+    /// Records that blobs were used now, only blobs which are already in log
+    ///
+    /// Log holds downloaded blobs, shared blobs served to peers are not added.
+    pub async fn touch_tracked(&self, hashes: &[Hash]) -> Result<()> {
+        let now = unix_time(SystemTime::now())?;
+        let mut times = self.times.lock().await;
+        for hash in hashes {
+            if let Some(time) = times.times.get_mut(hash) {
+                *time = now;
+                times.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    async fn forget(&self, hashes: &[Hash]) {
+        let mut times = self.times.lock().await;
+        for hash in hashes {
+            if times.times.remove(hash).is_some() {
+                times.dirty = true;
+            }
+        }
+    }
+
+    /// Writes changes since last flush to file
+    pub async fn flush(&self) -> Result<()> {
+        let mut times = self.times.lock().await;
+        if !times.dirty {
+            return Ok(());
+        }
+        let entries: Vec<(Hash, u64)> = times.times.iter().map(|(k, v)| (*k, *v)).collect();
+        files::write_atomic(&self.file, &postcard::to_stdvec(&entries)?).await?;
+        times.dirty = false;
+        Ok(())
+    }
+
+    /// Last access time of blob
+    ///
+    /// Blob not in log (downloaded by older version or log was lost) gets time when its data
+    /// were written, or current time for blobs without own data file, and the time is recorded.
+    async fn last_access(&self, hash: &Hash) -> Result<u64> {
+        let mut times = self.times.lock().await;
+        if let Some(time) = times.times.get(hash) {
+            return Ok(*time);
+        }
+        let data_file = PathOptions::new(&self.store_path).data_path(hash);
+        let written = match fs::metadata(&data_file).await {
+            Ok(metadata) => metadata.modified()?,
+            Err(_) => SystemTime::now(),
+        };
+        let time = unix_time(written)?;
+        times.times.insert(*hash, time);
+        times.dirty = true;
+        Ok(time)
+    }
+}
+
+/// File shared by reference, with metadata at import time to detect changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedReference {
+    pub hash: Hash,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl SharedReference {
+    pub async fn new(hash: Hash, path: PathBuf) -> Result<Self> {
+        let metadata = fs::metadata(&path).await?;
+        Ok(SharedReference {
+            hash,
+            path,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    /// Returns reason if file was changed or removed since import
+    pub async fn check(&self) -> Option<&'static str> {
+        match fs::metadata(&self.path).await {
+            Err(_) => Some("disappeared"),
+            Ok(metadata)
+                if metadata.len() != self.size
+                    || metadata.modified().ok() != Some(self.modified) =>
+            {
+                Some("changed")
+            }
+            Ok(_) => None,
+        }
+    }
+}
+
+/// Files shared by reference, persisted in store directory
+#[derive(Clone)]
+pub struct References {
+    file: PathBuf,
+    entries: Arc<Mutex<Vec<SharedReference>>>,
+}
+
+impl References {
+    pub async fn load(store_path: &Path) -> Result<Self> {
+        let file = store_path.join("references.bin");
+        let entries = if fs::try_exists(&file).await? {
+            postcard::from_bytes(&fs::read(&file).await?)?
+        } else {
+            vec![]
+        };
+        Ok(References {
+            file,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    pub async fn add(&self, reference: SharedReference) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+        entries.retain(|entry| entry.hash != reference.hash);
+        entries.push(reference);
+        self.save(&entries).await
+    }
+
+    pub async fn list(&self) -> Vec<SharedReference> {
+        self.entries.lock().await.clone()
+    }
+
+    pub async fn remove(&self, hash: &Hash) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+        let count = entries.len();
+        entries.retain(|entry| entry.hash != *hash);
+        if entries.len() == count {
+            return Ok(());
+        }
+        self.save(&entries).await
+    }
+
+    async fn save(&self, entries: &[SharedReference]) -> Result<()> {
+        files::write_atomic(&self.file, &postcard::to_stdvec(entries)?).await
+    }
+}
+
+/// Stops sharing blob, data is deleted by next garbage collection
+pub async fn unshare(store: &Store, hash: &Hash) -> Result<()> {
+    store.tags().delete(shared_tag(hash)).await?;
+    Ok(())
+}
+
+/// Removes tags of least recently used downloaded blobs until store fits into quota
+///
+/// Shared blobs are never evicted. Data of evicted blobs is deleted by next garbage collection.
+/// Returns evicted hashes.
+pub async fn enforce_quota(store: &Store, access: &AccessLog, quota: u64) -> Result<Vec<Hash>> {
+    let entries = entries(store).await?;
+    // untagged blobs are deleted by garbage collection anyway
+    let mut total: u64 = entries
+        .iter()
+        .filter(|entry| !entry.tags.is_empty())
+        .filter_map(StoreEntry::size)
+        .sum();
+    if total <= quota {
+        return Ok(vec![]);
+    }
+    let mut candidates = vec![];
+    for entry in entries
+        .into_iter()
+        .filter(|entry| entry.is_downloaded() && !entry.is_shared())
+    {
+        candidates.push((access.last_access(&entry.hash).await?, entry));
+    }
+    candidates.sort_by_key(|(time, _)| *time);
+    let mut evicted = vec![];
+    for (_, entry) in candidates {
+        if total <= quota {
+            break;
+        }
+        for tag in &entry.tags {
+            store.tags().delete(tag).await?;
+        }
+        total = total.saturating_sub(entry.size().unwrap_or(0));
+        evicted.push(entry.hash);
+    }
+    access.forget(&evicted).await;
+    Ok(evicted)
+}
+
+/// Exports complete blob to target file using given mode
+///
+/// Copy is made as copy-on-write clone where filesystem supports it. In reference mode store
+/// moves its data file to target and keeps referencing it there, so target must not be renamed
+/// afterwards. Small blobs inlined in store database are always written. Returns description
+/// of method store used.
+pub async fn export(
+    store: &Store,
+    hash: Hash,
+    target: &Path,
+    mode: ExportMode,
+) -> Result<&'static str> {
+    let blobs_mode = match mode {
+        ExportMode::Copy => blobs::ExportMode::Copy,
+        ExportMode::Reference => blobs::ExportMode::TryReference,
+    };
+    let mut progress = store
+        .blobs()
+        .export_with_opts(ExportOptions {
+            hash,
+            mode: blobs_mode,
+            target: target.to_path_buf(),
+        })
+        .stream()
+        .await;
+    let (mut size, mut copied) = (0, false);
+    while let Some(item) = progress.next().await {
+        match item {
+            ExportProgressItem::Size(total) => size = total,
+            // clone and move are done at once, only plain copy reports progress
+            ExportProgressItem::CopyProgress(_) => copied = true,
+            ExportProgressItem::Done => break,
+            ExportProgressItem::Error(e) => return Err(e.into()),
+        }
+    }
+    if copied || size <= InlineOptions::default().max_data_inlined {
+        return Ok("copied");
+    }
+    Ok(match mode {
+        ExportMode::Copy => "cloned",
+        ExportMode::Reference => "referenced",
+    })
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+async fn list(store: &Store) -> Result<Vec<String>> {
+    let entries = entries(store).await?;
+    if entries.is_empty() {
+        return Ok(vec!["Store is empty".into()]);
+    }
+    let mut lines = vec![];
+    let mut total = 0;
+    for entry in &entries {
+        let size = match (&entry.status, entry.size()) {
+            (BlobStatus::Complete { .. }, Some(size)) => format_size(size),
+            (BlobStatus::Partial { .. }, Some(size)) => format!("{} (partial)", format_size(size)),
+            (BlobStatus::Partial { .. }, None) => "partial".into(),
+            _ => "missing".into(),
+        };
+        total += entry.size().unwrap_or(0);
+        lines.push(format!("{}  {:>12}  {}", entry.hash, size, entry.origin()));
+    }
+    lines.push(format!(
+        "{} blobs, {} total",
+        entries.len(),
+        format_size(total)
+    ));
+    Ok(lines)
+}
+
+async fn remove(
+    store: &Store,
+    store_path: &Path,
+    references: &References,
+    hash: &str,
+) -> Result<Vec<String>> {
+    let prefix = hash.trim().to_lowercase();
+    if prefix.is_empty() {
+        anyhow::bail!("Missing hash");
+    }
+    let entries = entries(store).await?;
+    let mut matching = entries
+        .iter()
+        .filter(|entry| entry.hash.to_string().starts_with(&prefix));
+    let entry = match (matching.next(), matching.next()) {
+        (Some(entry), None) => entry,
+        (None, _) => anyhow::bail!("No blob with hash {prefix}"),
+        (Some(_), Some(_)) => anyhow::bail!("Hash prefix {prefix} is ambiguous"),
+    };
+    // watch would not share file again until it changes
+    if watch::shared_hashes(store_path)
+        .await?
+        .contains(&entry.hash)
+    {
+        anyhow::bail!(
+            "Blob {} is shared from watched directory, remove file there instead",
+            entry.hash
+        );
+    }
+    references.remove(&entry.hash).await?;
+    for tag in &entry.tags {
+        store.tags().delete(tag).await?;
+    }
+    Ok(vec![format!(
+        "Removed {} ({} tags), data is deleted by next garbage collection",
+        entry.hash,
+        entry.tags.len()
+    )])
+}
+
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Total size of files in store directory
+pub async fn disk_usage(store_path: &Path) -> Result<u64> {
+    let path = store_path.to_path_buf();
+    Ok(tokio::task::spawn_blocking(move || dir_size(&path)).await??)
+}
+
+async fn usage(store: &Store, store_path: &Path) -> Result<Vec<String>> {
+    let entries = entries(store).await?;
+    let (mut shared, mut downloaded, mut other) = (0, 0, 0);
+    for entry in &entries {
+        let size = entry.size().unwrap_or(0);
+        if entry.is_shared() {
+            shared += size;
+        } else if entry.is_downloaded() {
+            downloaded += size;
+        } else {
+            other += size;
+        }
+    }
+    Ok(vec![
+        format!("Store: {}", store_path.display()),
+        format!("Shared: {}", format_size(shared)),
+        format!("Downloaded: {}", format_size(downloaded)),
+        format!("Other: {}", format_size(other)),
+        format!("Disk usage: {}", format_size(disk_usage(store_path).await?)),
+    ])
+}
+
+/// Runs store command, returns lines to print
+pub async fn run_command(
+    store: &Store,
+    store_path: &Path,
+    references: &References,
+    cmd: &StoreCommand,
+) -> Result<Vec<String>> {
+    match cmd {
+        StoreCommand::List => list(store).await,
+        StoreCommand::Remove { hash } => remove(store, store_path, references, hash).await,
+        StoreCommand::Usage => usage(store, store_path).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[tokio::test]
+    async fn access_times_are_written_on_flush() {
+        let dir = TestDir::new();
+        let hash = Hash::new(b"downloaded");
+        let log = AccessLog::load(dir.root()).await.unwrap();
+        log.touch(hash).await.unwrap();
+        assert!(!dir.path("access.bin").exists());
+
+        log.flush().await.unwrap();
+        let loaded = AccessLog::load(dir.root()).await.unwrap();
+        assert!(loaded.times.lock().await.times.contains_key(&hash));
+    }
+
+    #[tokio::test]
+    async fn untracked_blob_is_not_oldest() {
+        let dir = TestDir::new();
+        let (old, untracked) = (Hash::new(b"old"), Hash::new(b"untracked"));
+        let log = AccessLog::load(dir.root()).await.unwrap();
+        log.times.lock().await.times.insert(old, 1);
+
+        let time = log.last_access(&untracked).await.unwrap();
+        assert!(time > 1);
+        assert_eq!(log.last_access(&untracked).await.unwrap(), time);
+    }
+
+    #[tokio::test]
+    async fn export_reports_method_used_by_store() {
+        let dir = TestDir::new();
+        let store = iroh_blobs::store::fs::FsStore::load(dir.path("store"))
+            .await
+            .unwrap();
+        let small = store.blobs().add_slice(b"small").await.unwrap().hash;
+        let data = vec![7u8; 100 * 1024];
+        let large = store.blobs().add_slice(&data).await.unwrap().hash;
+
+        let method = export(&store, small, &dir.path("small"), ExportMode::Copy)
+            .await
+            .unwrap();
+        assert_eq!(method, "copied");
+        let method = export(&store, large, &dir.path("large"), ExportMode::Copy)
+            .await
+            .unwrap();
+        assert!(["cloned", "copied"].contains(&method));
+        let method = export(
+            &store,
+            large,
+            &dir.path("referenced"),
+            ExportMode::Reference,
+        )
+        .await
+        .unwrap();
+        assert_eq!(method, "referenced");
+        assert_eq!(std::fs::read(dir.path("referenced")).unwrap(), data);
+        store.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! Watched directory, files placed there are shared automatically
+//!
+//! Directory is polled, file is reported once its size and modification time did not change
+//! for debounce period, so partially written files are not shared. Only regular files directly
+//! in directory are watched, hidden files (like temporary files of editors) are skipped.
+//! Shared files are recorded in store directory, so they are not shared again after restart.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use iroh_blobs::Hash;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::files;
+
+pub const DEFAULT_WATCH_DEBOUNCE: u64 = 3;
+
+struct WatchedFile {
+    size: u64,
+    modified: Option<SystemTime>,
+    /// When change was noticed
+    changed: Instant,
+    shared: bool,
+}
+
+/// Version of watched file which was shared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SharedFile {
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+    hash: Hash,
+}
+
+/// Hashes of watched files shared in this or previous run, as recorded in store directory
+pub async fn shared_hashes(store_path: &Path) -> Result<HashSet<Hash>> {
+    let state_file = store_path.join("watch.bin");
+    if !fs::try_exists(&state_file).await? {
+        return Ok(HashSet::new());
+    }
+    let shared: Vec<SharedFile> = postcard::from_bytes(&fs::read(&state_file).await?)?;
+    Ok(shared.into_iter().map(|file| file.hash).collect())
+}
+
+pub struct FolderWatch {
+    dir: PathBuf,
+    debounce: Duration,
+    files: HashMap<PathBuf, WatchedFile>,
+    state_file: PathBuf,
+    shared: HashMap<PathBuf, SharedFile>,
+}
+
+impl FolderWatch {
+    pub async fn load(dir: PathBuf, debounce: Duration, store_path: &Path) -> Result<Self> {
+        let state_file = store_path.join("watch.bin");
+        let shared: Vec<SharedFile> = if fs::try_exists(&state_file).await? {
+            postcard::from_bytes(&fs::read(&state_file).await?)?
+        } else {
+            vec![]
+        };
+        Ok(FolderWatch {
+            dir,
+            debounce,
+            files: HashMap::new(),
+            state_file,
+            shared: shared
+                .into_iter()
+                .map(|file| (file.path.clone(), file))
+                .collect(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Scans directory, returns new or changed files which settled since last scan
+    ///
+    /// Returned files are considered shared, they are reported again only after next change.
+    /// Files found unchanged since they were shared in previous run are not reported.
+    pub async fn scan(&mut self) -> Result<Vec<PathBuf>> {
+        let now = Instant::now();
+        let mut present = HashSet::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let (size, modified) = (metadata.len(), metadata.modified().ok());
+            let unchanged = self
+                .files
+                .get(&path)
+                .is_some_and(|file| file.size == size && file.modified == modified);
+            if !unchanged {
+                let shared = !self.files.contains_key(&path)
+                    && self
+                        .shared
+                        .get(&path)
+                        .is_some_and(|file| file.size == size && file.modified == modified);
+                let file = WatchedFile {
+                    size,
+                    modified,
+                    changed: now,
+                    shared,
+                };
+                self.files.insert(path.clone(), file);
+            }
+            present.insert(path);
+        }
+        self.files.retain(|path, _| present.contains(path));
+
+        let mut ready = Vec::new();
+        for (path, file) in self.files.iter_mut() {
+            if !file.shared && now.duration_since(file.changed) >= self.debounce {
+                file.shared = true;
+                ready.push(path.clone());
+            }
+        }
+        ready.sort();
+        Ok(ready)
+    }
+
+    /// Records that file reported by [`FolderWatch::scan`] was shared with `hash`
+    ///
+    /// Returns hash of previously shared version, if no other watched file has it.
+    pub async fn record(&mut self, path: &Path, hash: Hash) -> Result<Option<Hash>> {
+        let Some(file) = self.files.get(path) else {
+            return Ok(None);
+        };
+        let shared = SharedFile {
+            path: path.to_path_buf(),
+            size: file.size,
+            modified: file.modified,
+            hash,
+        };
+        let previous = self
+            .shared
+            .insert(path.to_path_buf(), shared)
+            .map(|previous| previous.hash)
+            .filter(|previous| self.shared.values().all(|file| file.hash != *previous));
+        self.save().await?;
+        Ok(previous)
+    }
+
+    async fn save(&self) -> Result<()> {
+        let shared: Vec<&SharedFile> = self.shared.values().collect();
+        files::write_atomic(&self.state_file, &postcard::to_stdvec(&shared)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    /// Temporary directory with watched directory and store directory
+    fn test_dir() -> TestDir {
+        let dir = TestDir::new();
+        std::fs::create_dir_all(dir.path("watch")).unwrap();
+        std::fs::create_dir_all(dir.path("store")).unwrap();
+        dir
+    }
+
+    fn watched(dir: &TestDir, name: &str) -> PathBuf {
+        dir.path("watch").join(name)
+    }
+
+    async fn load_watch(dir: &TestDir, debounce: Duration) -> FolderWatch {
+        FolderWatch::load(dir.path("watch"), debounce, &dir.path("store"))
+            .await
+            .unwrap()
+    }
+
+    /// Makes files look like they changed long ago
+    fn settle(watch: &mut FolderWatch) {
+        for file in watch.files.values_mut() {
+            file.changed -= watch.debounce;
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_reported_after_debounce() {
+        let dir = test_dir();
+        let mut watch = load_watch(&dir, Duration::from_secs(60)).await;
+        std::fs::write(watched(&dir, "a.txt"), b"a").unwrap();
+        std::fs::write(watched(&dir, ".hidden"), b"h").unwrap();
+        assert!(watch.scan().await.unwrap().is_empty());
+        settle(&mut watch);
+        assert_eq!(watch.scan().await.unwrap(), [watched(&dir, "a.txt")]);
+        // reported only once until it changes
+        assert!(watch.scan().await.unwrap().is_empty());
+
+        std::fs::write(watched(&dir, "a.txt"), b"changed").unwrap();
+        assert!(watch.scan().await.unwrap().is_empty());
+        settle(&mut watch);
+        assert_eq!(watch.scan().await.unwrap(), [watched(&dir, "a.txt")]);
+    }
+
+    #[tokio::test]
+    async fn record_returns_replaced_hash() {
+        let dir = test_dir();
+        let mut watch = load_watch(&dir, Duration::ZERO).await;
+        let (a, b) = (watched(&dir, "a.txt"), watched(&dir, "b.txt"));
+        std::fs::write(&a, b"same").unwrap();
+        std::fs::write(&b, b"same").unwrap();
+        assert_eq!(watch.scan().await.unwrap(), [a.clone(), b.clone()]);
+        let same = Hash::new(b"same");
+        assert_eq!(watch.record(&a, same).await.unwrap(), None);
+        assert_eq!(watch.record(&b, same).await.unwrap(), None);
+
+        std::fs::write(&a, b"new a").unwrap();
+        assert_eq!(watch.scan().await.unwrap(), vec![a.clone()]);
+        // previous version is still shared by other file
+        assert_eq!(watch.record(&a, Hash::new(b"new a")).await.unwrap(), None);
+
+        std::fs::write(&b, b"new b").unwrap();
+        assert_eq!(watch.scan().await.unwrap(), vec![b.clone()]);
+        assert_eq!(
+            watch.record(&b, Hash::new(b"new b")).await.unwrap(),
+            Some(same)
+        );
+    }
+
+    #[tokio::test]
+    async fn shared_files_are_not_reported_after_restart() {
+        let dir = test_dir();
+        let (a, b) = (watched(&dir, "a.txt"), watched(&dir, "b.txt"));
+        std::fs::write(&a, b"a").unwrap();
+        std::fs::write(&b, b"b").unwrap();
+        let mut watch = load_watch(&dir, Duration::ZERO).await;
+        watch.scan().await.unwrap();
+        watch.record(&a, Hash::new(b"a")).await.unwrap();
+
+        let mut watch = load_watch(&dir, Duration::ZERO).await;
+        assert_eq!(watch.scan().await.unwrap(), [b]);
+    }
+}